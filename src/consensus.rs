@@ -4,10 +4,17 @@ use log::{debug, error};
 use std::collections::HashMap;
 use std::net::IpAddr;
 use std::option::Option;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 use std::vec::Vec;
 
 use crate::sources::Family;
 
+/// Longest time a source is skipped for, whatever the delay it asked for
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(3600);
+/// Time a source replying HTTP 429 without `Retry-After` is skipped for
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
+
 /// Type alias for easier usage of the library
 pub type Sources = Vec<Box<dyn sources::Source>>;
 
@@ -27,10 +34,15 @@ pub enum Policy {
 
 /// Consensus system that aggregates the various sources of information and returns the most common
 /// reply
+///
+/// Sources that reply asking to be contacted later (e.g. HTTP 429 with `Retry-After`) are skipped
+/// until the requested time has passed, for at most an hour. An HTTP 429 without `Retry-After`
+/// skips the source for a minute.
 pub struct Consensus {
     voters: Sources,
     policy: Policy,
     family: Family,
+    retry_after: Mutex<Vec<Option<SystemTime>>>,
 }
 
 /// Consensus builder
@@ -81,6 +93,7 @@ impl ConsensusBuilder {
     /// Returns the configured consensus struct from the builder
     pub fn build(self) -> Consensus {
         Consensus {
+            retry_after: Mutex::new(vec![None; self.voters.len()]),
             voters: self.voters,
             policy: self.policy,
             family: self.family,
@@ -97,14 +110,49 @@ impl Consensus {
         }
     }
 
+    /// Returns true if the source asked not to be queried yet
+    fn is_rate_limited(&self, pos: usize) -> bool {
+        let retry_after = self.retry_after.lock().unwrap();
+        match retry_after[pos] {
+            Some(time) if time > SystemTime::now() => {
+                debug!("Skipping rate limited source {}", self.voters[pos]);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remembers when a rate limited source can be queried again, up to `MAX_RETRY_AFTER` from
+    /// now
+    fn update_rate_limit(&self, pos: usize, result: &sources::IpResult) {
+        let now = SystemTime::now();
+        let mut retry_after = self.retry_after.lock().unwrap();
+        retry_after[pos] = match result {
+            Err(sources::Error::RateLimited { retry_after, .. }) => {
+                Some((*retry_after).min(now + MAX_RETRY_AFTER))
+            }
+            Err(sources::Error::HttpStatus(reqwest::StatusCode::TOO_MANY_REQUESTS)) => {
+                Some(now + DEFAULT_RETRY_AFTER)
+            }
+            _ => None,
+        };
+    }
+
     async fn all(&self) -> Option<IpAddr> {
-        let results =
-            futures::future::join_all(self.voters.iter().map(|voter| voter.get_ip(self.family)))
-                .await;
+        let positions: Vec<_> = (0..self.voters.len())
+            .filter(|pos| !self.is_rate_limited(*pos))
+            .collect();
+        let results = futures::future::join_all(
+            positions
+                .iter()
+                .map(|pos| self.voters[*pos].get_ip(self.family)),
+        )
+        .await;
 
         debug!("Results {:?}", results);
         let mut accumulate = HashMap::new();
-        for (pos, result) in results.into_iter().enumerate() {
+        for (pos, result) in positions.into_iter().zip(results) {
+            self.update_rate_limit(pos, &result);
             match result {
                 Ok(result) => {
                     accumulate
@@ -117,16 +165,20 @@ impl Consensus {
         }
 
         let mut ordered_output: Vec<_> = accumulate.iter().collect();
-        ordered_output.sort_unstable_by(|(_, a), (_, b)| a.cmp(b));
+        ordered_output.sort_unstable_by_key(|(_, c)| **c);
         debug!("Sorted results {:?}", ordered_output);
 
         ordered_output.pop().map(|x| *x.0)
     }
 
     async fn first(&self) -> Option<IpAddr> {
-        for voter in &self.voters {
+        for (pos, voter) in self.voters.iter().enumerate() {
+            if self.is_rate_limited(pos) {
+                continue;
+            }
             let result = voter.get_ip(self.family).await;
             debug!("Results {:?}", result);
+            self.update_rate_limit(pos, &result);
             if result.is_ok() {
                 return result.ok();
             }
@@ -137,7 +189,7 @@ impl Consensus {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
     use crate::sources::MockSource;
@@ -147,7 +199,7 @@ mod tests {
 
     const IP0: IpAddr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
 
-    fn make_success(ip: IpAddr) -> Box<dyn sources::Source> {
+    pub(crate) fn make_success(ip: IpAddr) -> Box<dyn sources::Source> {
         let mut mock = MockSource::new();
         mock.expect_get_ip()
             .with(eq(Family::Any))
@@ -170,6 +222,33 @@ mod tests {
         Box::new(mock)
     }
 
+    fn make_rate_limited(retry_after: SystemTime) -> Box<dyn sources::Source> {
+        let mut mock = MockSource::new();
+        mock.expect_get_ip()
+            .with(eq(Family::Any))
+            .times(1)
+            .returning(move |_| {
+                Box::pin(futures::future::ready(Err(sources::Error::RateLimited {
+                    status: reqwest::StatusCode::TOO_MANY_REQUESTS,
+                    retry_after,
+                })))
+            });
+        Box::new(mock)
+    }
+
+    fn make_status(status: reqwest::StatusCode) -> Box<dyn sources::Source> {
+        let mut mock = MockSource::new();
+        mock.expect_get_ip()
+            .with(eq(Family::Any))
+            .times(1)
+            .returning(move |_| {
+                Box::pin(futures::future::ready(Err(sources::Error::HttpStatus(
+                    status,
+                ))))
+            });
+        Box::new(mock)
+    }
+
     fn make_untouched() -> Box<dyn sources::Source> {
         let mut mock = MockSource::new();
         mock.expect_get_ip().with(eq(Family::Any)).times(0);
@@ -271,4 +350,72 @@ mod tests {
         let value = block_on(result);
         assert_eq!(Some(IP0), value);
     }
+
+    #[test]
+    fn test_rate_limited_skipped() {
+        let later = SystemTime::now() + std::time::Duration::from_secs(3600);
+        for policy in [Policy::All, Policy::First].iter() {
+            let consensus = ConsensusBuilder::new()
+                .add_sources(vec![make_rate_limited(later)])
+                .policy(*policy)
+                .build();
+            assert_eq!(None, block_on(consensus.get_consensus()));
+            // The mock fails if it's queried a second time
+            assert_eq!(None, block_on(consensus.get_consensus()));
+        }
+    }
+
+    #[test]
+    fn test_rate_limit_expired() {
+        let mut mock = MockSource::new();
+        let mut results = vec![
+            Ok(IP0),
+            Err(sources::Error::RateLimited {
+                status: reqwest::StatusCode::TOO_MANY_REQUESTS,
+                retry_after: SystemTime::UNIX_EPOCH,
+            }),
+        ];
+        mock.expect_get_ip()
+            .with(eq(Family::Any))
+            .times(2)
+            .returning(move |_| Box::pin(futures::future::ready(results.pop().unwrap())));
+        let consensus = ConsensusBuilder::new()
+            .add_sources(vec![Box::new(mock) as Box<dyn sources::Source>])
+            .build();
+        assert_eq!(None, block_on(consensus.get_consensus()));
+        assert_eq!(Some(IP0), block_on(consensus.get_consensus()));
+    }
+
+    #[test]
+    fn test_rate_limit_capped() {
+        let consensus = ConsensusBuilder::new()
+            .add_sources(vec![make_rate_limited(
+                SystemTime::now() + Duration::from_secs(365 * 24 * 3600),
+            )])
+            .build();
+        assert_eq!(None, block_on(consensus.get_consensus()));
+        let retry_after = consensus.retry_after.lock().unwrap()[0].expect("rate limited");
+        assert!(retry_after <= SystemTime::now() + MAX_RETRY_AFTER);
+    }
+
+    #[test]
+    fn test_rate_limited_without_retry_after() {
+        for policy in [Policy::All, Policy::First].iter() {
+            let consensus = ConsensusBuilder::new()
+                .add_sources(vec![make_status(reqwest::StatusCode::TOO_MANY_REQUESTS)])
+                .policy(*policy)
+                .build();
+            assert_eq!(None, block_on(consensus.get_consensus()));
+            // The mock fails if it's queried a second time
+            assert_eq!(None, block_on(consensus.get_consensus()));
+            let retry_after = consensus.retry_after.lock().unwrap()[0].expect("rate limited");
+            assert!(retry_after <= SystemTime::now() + DEFAULT_RETRY_AFTER);
+        }
+
+        let consensus = ConsensusBuilder::new()
+            .add_sources(vec![make_status(reqwest::StatusCode::BAD_GATEWAY)])
+            .build();
+        assert_eq!(None, block_on(consensus.get_consensus()));
+        assert_eq!(None, consensus.retry_after.lock().unwrap()[0]);
+    }
 }
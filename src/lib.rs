@@ -1,14 +1,19 @@
 //! Crate to figure out the system external IP
 mod consensus;
 mod sources;
+mod topology;
 
 pub use consensus::*;
 pub use sources::*;
+pub use topology::*;
 
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 /// For ease of use a single async function is enough to obtain the IP trying with all the default
 /// sources enabled.
+///
+/// Every call builds a new `Consensus`, so sources asking to be contacted later with `Retry-After`
+/// are queried again by the next call. Keep a `Consensus` around to honor it.
 #[deprecated]
 pub async fn get_ip() -> Option<IpAddr> {
     let sources: Sources = get_sources();
@@ -18,8 +23,17 @@ pub async fn get_ip() -> Option<IpAddr> {
 
 /// For ease of use a single async function is enough to obtain the IPv4 trying with all the default
 /// sources enabled.
+///
+/// Every call builds a new `Consensus`, so sources asking to be contacted later with `Retry-After`
+/// are queried again by the next call. Keep a `Consensus` around to honor it.
 pub async fn get_ipv4() -> Option<Ipv4Addr> {
-    let sources: Sources = get_sources();
+    get_ipv4_with_bootstrap(BootstrapResolver::default()).await
+}
+
+/// Same as `get_ipv4`, looking up the nameservers of the DNS sources with the given bootstrap
+/// resolver.
+pub async fn get_ipv4_with_bootstrap(bootstrap: BootstrapResolver) -> Option<Ipv4Addr> {
+    let sources: Sources = get_sources_with_bootstrap(bootstrap);
     let consensus = ConsensusBuilder::new()
         .family(Family::IPv4)
         .add_sources(sources)
@@ -34,8 +48,17 @@ pub async fn get_ipv4() -> Option<Ipv4Addr> {
 
 /// For ease of use a single async function is enough to obtain the IPv6 trying with all the default
 /// sources enabled.
+///
+/// Every call builds a new `Consensus`, so sources asking to be contacted later with `Retry-After`
+/// are queried again by the next call. Keep a `Consensus` around to honor it.
 pub async fn get_ipv6() -> Option<Ipv6Addr> {
-    let sources: Sources = get_sources();
+    get_ipv6_with_bootstrap(BootstrapResolver::default()).await
+}
+
+/// Same as `get_ipv6`, looking up the nameservers of the DNS sources with the given bootstrap
+/// resolver.
+pub async fn get_ipv6_with_bootstrap(bootstrap: BootstrapResolver) -> Option<Ipv6Addr> {
+    let sources: Sources = get_sources_with_bootstrap(bootstrap);
     let consensus = ConsensusBuilder::new()
         .family(Family::IPv6)
         .add_sources(sources)
@@ -47,3 +70,35 @@ pub async fn get_ipv6() -> Option<Ipv6Addr> {
         ipv6
     })
 }
+
+/// Returns the public address of the recursive resolver configured in the system, as seen by the
+/// authoritative nameservers.
+///
+/// It can differ from the external IP, e.g. when DNS queries leak outside of a VPN tunnel.
+pub async fn get_resolver_egress(family: Family) -> Option<IpAddr> {
+    let sources: Sources = get_resolver_egress_sources();
+    let consensus = ConsensusBuilder::new()
+        .family(family)
+        .add_sources(sources)
+        .build();
+    consensus.get_consensus().await
+}
+
+/// Detects the NAT topology comparing the WAN address of the local gateway, with the IGD and
+/// NAT-PMP sources enabled, to the IPv4 address found by the default sources.
+pub async fn get_nat_topology() -> Option<NatTopology> {
+    let public: Sources = get_dns_sources::<Sources>()
+        .into_iter()
+        .chain(get_http_sources::<Sources>())
+        .collect();
+    let public = ConsensusBuilder::new()
+        .family(Family::IPv4)
+        .add_sources(public)
+        .build();
+    let gateway = ConsensusBuilder::new()
+        .family(Family::IPv4)
+        .policy(Policy::First)
+        .add_sources(get_local_gateway_sources::<Sources>())
+        .build();
+    detect_nat_topology(&public, &gateway).await
+}
@@ -0,0 +1,110 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+
+use hickory_resolver::net::runtime::iocompat::AsyncIoTokioAsStd;
+use hickory_resolver::net::runtime::{
+    RuntimeProvider, TokioHandle, TokioRuntimeProvider, TokioTime,
+};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+
+/// Timeout for TCP connections when the resolver doesn't provide one
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tokio runtime provider for the DNS resolvers that optionally binds every socket to a network
+/// interface (`SO_BINDTODEVICE`).
+#[derive(Clone, Default)]
+pub(crate) struct BindRuntimeProvider {
+    runtime: TokioRuntimeProvider,
+    interface: Option<String>,
+}
+
+impl BindRuntimeProvider {
+    pub(crate) fn new(interface: Option<String>) -> Self {
+        Self {
+            runtime: TokioRuntimeProvider::default(),
+            interface,
+        }
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_udp_device(socket: &UdpSocket, interface: Option<&str>) -> io::Result<()> {
+    match interface {
+        Some(interface) => socket.bind_device(Some(interface.as_bytes())),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_udp_device(_socket: &UdpSocket, _interface: Option<&str>) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_tcp_device(socket: &TcpSocket, interface: Option<&str>) -> io::Result<()> {
+    match interface {
+        Some(interface) => socket.bind_device(Some(interface.as_bytes())),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_tcp_device(_socket: &TcpSocket, _interface: Option<&str>) -> io::Result<()> {
+    Ok(())
+}
+
+impl RuntimeProvider for BindRuntimeProvider {
+    type Handle = TokioHandle;
+    type Timer = TokioTime;
+    type Udp = UdpSocket;
+    type Tcp = AsyncIoTokioAsStd<TcpStream>;
+
+    fn create_handle(&self) -> Self::Handle {
+        self.runtime.create_handle()
+    }
+
+    fn connect_tcp(
+        &self,
+        server_addr: SocketAddr,
+        bind_addr: Option<SocketAddr>,
+        timeout: Option<Duration>,
+    ) -> Pin<Box<dyn Send + Future<Output = Result<Self::Tcp, io::Error>>>> {
+        let interface = self.interface.clone();
+        Box::pin(async move {
+            let socket = match server_addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4(),
+                SocketAddr::V6(_) => TcpSocket::new_v6(),
+            }?;
+            bind_tcp_device(&socket, interface.as_deref())?;
+            if let Some(bind_addr) = bind_addr {
+                socket.bind(bind_addr)?;
+            }
+            socket.set_nodelay(true)?;
+
+            let timeout = timeout.unwrap_or(CONNECT_TIMEOUT);
+            match tokio::time::timeout(timeout, socket.connect(server_addr)).await {
+                Ok(stream) => Ok(AsyncIoTokioAsStd(stream?)),
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "TCP connect timed out",
+                )),
+            }
+        })
+    }
+
+    fn bind_udp(
+        &self,
+        local_addr: SocketAddr,
+        _server_addr: SocketAddr,
+    ) -> Pin<Box<dyn Send + Future<Output = Result<Self::Udp, io::Error>>>> {
+        let interface = self.interface.clone();
+        Box::pin(async move {
+            let socket = UdpSocket::bind(local_addr).await?;
+            bind_udp_device(&socket, interface.as_deref())?;
+            Ok(socket)
+        })
+    }
+}
@@ -1,28 +1,371 @@
+use crate::sources::bind::BindRuntimeProvider;
+use crate::sources::doh::DoHSource;
 use crate::sources::interfaces::{Error, Family, IpFuture, IpResult, Source};
 use log::trace;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
 
-use hickory_resolver::TokioResolver;
+use hickory_resolver::Resolver;
 use hickory_resolver::config::*;
-use hickory_resolver::net::runtime::TokioRuntimeProvider;
-use hickory_resolver::proto::rr::RData;
+use hickory_resolver::net::NetError;
+use hickory_resolver::net::client::Client;
+use hickory_resolver::net::tcp::TcpClientStream;
+use hickory_resolver::net::tls::{client_config, tls_client_connect_with_bind_addr};
+use hickory_resolver::net::udp::UdpClientStream;
+use hickory_resolver::net::xfer::{DnsHandle, DnsMultiplexer, DnsRequestSender, FirstAnswer};
+#[cfg(feature = "dnssec")]
+use hickory_resolver::proto::dnssec::rdata::DNSKEY;
+use hickory_resolver::proto::op::{DnsRequestOptions, DnsResponse, Query};
+use hickory_resolver::proto::rr::{DNSClass, Name, RData, Record, RecordType};
+use regex::Regex;
+use rustls_pki_types::ServerName;
+
+type DNSResolver = Resolver<BindRuntimeProvider>;
+
+/// Resolver pointing at the nameserver of a source, and the addresses of the nameserver
+#[derive(Clone)]
+struct Upstream {
+    resolver: DNSResolver,
+    addresses: Vec<SocketAddr>,
+    /// Keys of the zone of the record, empty when the zone is unsigned or DNSSEC is disabled
+    #[cfg(feature = "dnssec")]
+    zone_keys: Vec<DNSKEY>,
+}
+
+/// Entries shared by every source, reused until they expire
+type Cache<K, V> = LazyLock<Mutex<HashMap<K, (V, Instant)>>>;
+
+/// Addresses of the nameservers found by the bootstrap resolver
+static NAME_SERVERS: Cache<BootstrapKey, Vec<IpAddr>> = LazyLock::new(Default::default);
+/// Resolvers pointing at the nameservers, valid as long as the nameserver addresses
+static UPSTREAMS: Cache<UpstreamKey, Upstream> = LazyLock::new(Default::default);
+
+/// Returns the entry of the cache and its expiry, dropping it once expired
+fn cache_get<K: Eq + Hash, V: Clone>(cache: &Cache<K, V>, key: &K) -> Option<(V, Instant)> {
+    let mut cache = cache.lock().unwrap();
+    match cache.get(key) {
+        Some((value, valid_until)) if *valid_until > Instant::now() => {
+            Some((value.clone(), *valid_until))
+        }
+        Some(_) => {
+            cache.remove(key);
+            None
+        }
+        None => None,
+    }
+}
+
+fn cache_insert<K: Eq + Hash, V>(cache: &Cache<K, V>, key: K, value: V, valid_until: Instant) {
+    cache.lock().unwrap().insert(key, (value, valid_until));
+}
+
+/// Identifies the lookup of the nameserver addresses of a source
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct BootstrapKey {
+    server: String,
+    bootstrap: BootstrapResolver,
+    local_address: Option<IpAddr>,
+    interface: Option<String>,
+    #[cfg(feature = "dnssec")]
+    dnssec: bool,
+}
+
+/// Identifies the resolver querying the nameserver of a source
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct UpstreamKey {
+    bootstrap: BootstrapKey,
+    record: String,
+    family: Family,
+    transport: Transport,
+    timeout: Option<Duration>,
+    attempts: Option<usize>,
+    edns: Option<bool>,
+}
 
 #[derive(Debug, Clone, Copy)]
 pub enum QueryType {
     TXT,
     A,
     AAAA,
+    /// TXT record in the CHAOS class (e.g. `whoami.cloudflare` on 1.1.1.1)
+    ChaosTXT,
+}
+
+/// Function extracting an address from a TXT string
+pub type ExtractFn = Arc<dyn Fn(&str) -> Option<IpAddr> + Send + Sync>;
+
+/// Extracts the address from the strings of a TXT record
+///
+/// Strings not yielding an address are skipped.
+#[derive(Clone, Default)]
+pub enum TxtExtractor {
+    /// The whole string is the address
+    #[default]
+    Address,
+    /// The address follows the given prefix (e.g. `"ip="`)
+    Prefix(String),
+    /// The address is the first capture group of the expression, or the whole match without
+    /// groups
+    Regex(Regex),
+    /// Custom extraction function
+    Custom(ExtractFn),
+}
+
+impl TxtExtractor {
+    /// Returns an extractor calling the given function on each string
+    pub fn custom<F>(extract: F) -> Self
+    where
+        F: Fn(&str) -> Option<IpAddr> + Send + Sync + 'static,
+    {
+        TxtExtractor::Custom(Arc::new(extract))
+    }
+
+    fn extract(&self, data: &str) -> Option<IpAddr> {
+        match self {
+            TxtExtractor::Address => data.trim().parse().ok(),
+            TxtExtractor::Prefix(prefix) => data.trim().strip_prefix(prefix)?.trim().parse().ok(),
+            TxtExtractor::Regex(regex) => {
+                let captures = regex.captures(data)?;
+                captures
+                    .get(1)
+                    .or_else(|| captures.get(0))?
+                    .as_str()
+                    .parse()
+                    .ok()
+            }
+            TxtExtractor::Custom(extract) => extract(data),
+        }
+    }
+}
+
+impl std::fmt::Debug for TxtExtractor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TxtExtractor::Address => f.write_str("Address"),
+            TxtExtractor::Prefix(prefix) => f.debug_tuple("Prefix").field(prefix).finish(),
+            TxtExtractor::Regex(regex) => f.debug_tuple("Regex").field(&regex.as_str()).finish(),
+            TxtExtractor::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+/// Transport used to query the nameserver of a DNS source
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub enum Transport {
+    /// Plain DNS over UDP
+    #[default]
+    Udp,
+    /// Plain DNS over TCP
+    Tcp,
+    /// Plain DNS over UDP, falling back to TCP
+    UdpAndTcp,
+    /// DNS over TLS on port 853.
+    ///
+    /// The certificate of the nameserver is verified against `server_name`, or against the
+    /// nameserver hostname if not set.
+    Tls { server_name: Option<String> },
+}
+
+/// Resolver used to look up the address of the nameserver of a DNS source
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub enum BootstrapResolver {
+    /// Google public DNS
+    #[default]
+    Default,
+    /// Resolvers configured in the system (`/etc/resolv.conf` on Unix)
+    System,
+    /// Explicit resolver addresses, queried over UDP and TCP
+    Servers(Vec<IpAddr>),
+}
+
+pub struct DNSSourceBuilder {
+    server: String,
+    record_type: QueryType,
+    record: String,
+    local_address: Option<IpAddr>,
+    interface: Option<String>,
+    bootstrap: BootstrapResolver,
+    transport: Transport,
+    timeout: Option<Duration>,
+    attempts: Option<usize>,
+    edns: Option<bool>,
+    extractor: TxtExtractor,
+    name_servers: Vec<SocketAddr>,
+    recursive: bool,
+    #[cfg(feature = "dnssec")]
+    dnssec: bool,
+}
+impl DNSSourceBuilder {
+    pub fn new<S: Into<String>, R: Into<String>>(
+        server: S,
+        record_type: QueryType,
+        record: R,
+    ) -> Self {
+        Self {
+            server: server.into(),
+            record_type,
+            record: record.into(),
+            local_address: None,
+            interface: None,
+            bootstrap: BootstrapResolver::default(),
+            transport: Transport::default(),
+            timeout: None,
+            attempts: None,
+            edns: None,
+            extractor: TxtExtractor::default(),
+            name_servers: Vec::new(),
+            recursive: false,
+            #[cfg(feature = "dnssec")]
+            dnssec: false,
+        }
+    }
+    /// Sets the resolver used to look up the address of the nameserver
+    pub fn with_bootstrap(mut self, bootstrap: BootstrapResolver) -> Self {
+        self.bootstrap = bootstrap;
+        self
+    }
+    /// Sends the queries from the given local address.
+    ///
+    /// Only nameservers of the same family of the address will be used.
+    pub fn with_local_address(mut self, local_address: IpAddr) -> Self {
+        self.local_address = Some(local_address);
+        self
+    }
+    /// Binds the sockets to the given network interface (`SO_BINDTODEVICE`)
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    pub fn with_interface<S: Into<String>>(mut self, interface: S) -> Self {
+        self.interface = Some(interface.into());
+        self
+    }
+    /// Sets the transport used to query the nameserver
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+    /// Sets the timeout of each query (5 seconds by default)
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+    /// Sets how many times a failed query is retried (2 by default)
+    pub fn with_attempts(mut self, attempts: usize) -> Self {
+        self.attempts = Some(attempts);
+        self
+    }
+    /// Enables or disables EDNS(0) in the queries
+    pub fn with_edns(mut self, edns: bool) -> Self {
+        self.edns = Some(edns);
+        self
+    }
+    /// Sets how the address is extracted from TXT records
+    pub fn with_txt_extractor(mut self, extractor: TxtExtractor) -> Self {
+        self.extractor = extractor;
+        self
+    }
+    /// Queries the given nameserver addresses, of either family, instead of looking up the
+    /// server hostname.
+    ///
+    /// The server is then only used to authenticate the nameserver over TLS. The ports replace the
+    /// default port of the transport.
+    pub fn with_name_servers<I: IntoIterator<Item = SocketAddr>>(
+        mut self,
+        name_servers: I,
+    ) -> Self {
+        self.name_servers = name_servers.into_iter().collect();
+        self
+    }
+    /// Sends the query through the bootstrap resolver instead of the nameserver of the server.
+    ///
+    /// Reflectors (e.g. `whoami.akamai.net`) then report the public address of the recursive
+    /// resolver, rather than the external IP of the host. The server is only used as a label.
+    pub fn with_recursive_query(mut self) -> Self {
+        self.recursive = true;
+        self
+    }
+    /// Validates the lookups of the bootstrap resolver, requiring DNSSEC secure addresses of the
+    /// nameserver, and checks the signatures of the replies of the nameserver when the zone of
+    /// the record is signed.
+    ///
+    /// The nameserver answers authoritatively and can't provide the chain of trust, so the keys
+    /// of the zone are looked up through the bootstrap resolver, which must be a validating
+    /// recursive resolver. Replies from unsigned zones are accepted, CHAOS class replies are not
+    /// validated.
+    ///
+    /// The nameservers of the opendns and google default sources are in unsigned zones, so those
+    /// sources fail with validation enabled. The Cloudflare CHAOS sources, queried by address,
+    /// and the DNS over HTTPS source keep working.
+    #[cfg(feature = "dnssec")]
+    pub fn with_dnssec(mut self, dnssec: bool) -> Self {
+        self.dnssec = dnssec;
+        self
+    }
+    pub fn build(self) -> DNSSource {
+        let Self {
+            server,
+            record_type,
+            record,
+            local_address,
+            interface,
+            bootstrap,
+            transport,
+            timeout,
+            attempts,
+            edns,
+            extractor,
+            name_servers,
+            recursive,
+            #[cfg(feature = "dnssec")]
+            dnssec,
+        } = self;
+        DNSSource {
+            server,
+            record_type,
+            record,
+            local_address,
+            interface,
+            bootstrap,
+            transport,
+            timeout,
+            attempts,
+            edns,
+            extractor,
+            name_servers,
+            recursive,
+            #[cfg(feature = "dnssec")]
+            dnssec,
+        }
+    }
 }
 
 /// DNS Source of the external ip
 ///
-/// It expects a DNS server to target for a query (currently only A and TXT), to retrive in the
+/// It expects a DNS server to target for a query (A, AAAA, TXT or CHAOS TXT), to retrive in the
 /// reply of the message the IP.
 /// A few services are known for replying with the IP of the query sender.
+///
+/// The addresses of the nameserver and the resolver pointing at it are cached, and shared between
+/// all the sources with the same server and options,
+/// for as long as the TTL of the nameserver addresses allows.
 #[derive(Debug, Clone)]
 pub struct DNSSource {
     server: String,
     record_type: QueryType,
     record: String,
+    local_address: Option<IpAddr>,
+    interface: Option<String>,
+    bootstrap: BootstrapResolver,
+    transport: Transport,
+    timeout: Option<Duration>,
+    attempts: Option<usize>,
+    edns: Option<bool>,
+    extractor: TxtExtractor,
+    name_servers: Vec<SocketAddr>,
+    recursive: bool,
+    #[cfg(feature = "dnssec")]
+    dnssec: bool,
 }
 
 impl DNSSource {
@@ -31,22 +374,31 @@ impl DNSSource {
         record_type: QueryType,
         record: R,
     ) -> Self {
-        DNSSource {
-            server: server.into(),
-            record_type,
-            record: record.into(),
-        }
+        DNSSourceBuilder::new(server, record_type, record).build()
     }
     fn source<R: Into<String>>(
         server: String,
         record_type: QueryType,
         record: R,
+        bootstrap: &BootstrapResolver,
     ) -> Box<dyn Source> {
-        Box::new(DNSSource {
-            server,
-            record_type,
-            record: record.into(),
-        })
+        Box::new(
+            DNSSourceBuilder::new(server, record_type, record)
+                .with_bootstrap(bootstrap.clone())
+                .build(),
+        )
+    }
+    /// Returns a source querying the nameserver at the address on port 53, without bootstrap
+    fn name_server_source<R: Into<String>>(
+        address: IpAddr,
+        record_type: QueryType,
+        record: R,
+    ) -> Box<dyn Source> {
+        Box::new(
+            DNSSourceBuilder::new(address.to_string(), record_type, record)
+                .with_name_servers([SocketAddr::new(address, 53)])
+                .build(),
+        )
     }
 }
 
@@ -61,98 +413,662 @@ impl std::fmt::Display for DNSSource {
 }
 
 impl DNSSource {
-    async fn get_resolver(self: &DNSSource, family: Family) -> Result<TokioResolver, Error> {
+    /// Returns the name used to authenticate the nameserver over TLS
+    fn tls_name(&self) -> &str {
+        match &self.transport {
+            Transport::Tls {
+                server_name: Some(server_name),
+            } => server_name,
+            _ => &self.server,
+        }
+    }
+
+    fn name_server_config(&self, ip: IpAddr) -> NameServerConfig {
+        match self.transport {
+            Transport::Udp => NameServerConfig::udp(ip),
+            Transport::Tcp => NameServerConfig::tcp(ip),
+            Transport::UdpAndTcp => NameServerConfig::udp_and_tcp(ip),
+            Transport::Tls { .. } => NameServerConfig::tls(ip, Arc::from(self.tls_name())),
+        }
+    }
+
+    /// Returns the resolver options with the configured timeout, attempts and EDNS settings
+    fn resolver_opts(&self) -> ResolverOpts {
         let mut resolver_opts = ResolverOpts::default();
+        if let Some(timeout) = self.timeout {
+            resolver_opts.timeout = timeout;
+        }
+        if let Some(attempts) = self.attempts {
+            resolver_opts.attempts = attempts;
+        }
+        if let Some(edns) = self.edns {
+            resolver_opts.edns0 = edns;
+        }
+        resolver_opts
+    }
+
+    /// Returns the options of the resolvers querying the bootstrap resolver, validating the
+    /// replies when DNSSEC is enabled
+    fn bootstrap_opts(&self, resolver_opts: &ResolverOpts) -> ResolverOpts {
+        #[allow(unused_mut)]
+        let mut resolver_opts = resolver_opts.clone();
+        #[cfg(feature = "dnssec")]
+        {
+            resolver_opts.validate = self.dnssec;
+        }
+        resolver_opts
+    }
+
+    fn bootstrap_resolver(&self, provider: BindRuntimeProvider) -> Result<DNSResolver, Error> {
+        let mut builder = DNSResolver::builder_with_config(self.bootstrap_config()?, provider);
+        *builder.options_mut() = self.bootstrap_opts(&self.resolver_opts());
+        // Addresses of both families are looked up, to be shared by the sources of either family
+        builder.options_mut().ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        Ok(builder.build()?)
+    }
+
+    /// Restricts the nameservers to the family of the local address and binds them to it
+    fn bind_name_servers<I>(&self, name_servers: I) -> Vec<NameServerConfig>
+    where
+        I: IntoIterator<Item = NameServerConfig>,
+    {
+        name_servers
+            .into_iter()
+            .filter_map(|mut name_server| {
+                if let Some(local_address) = self.local_address {
+                    if local_address.is_ipv4() != name_server.ip.is_ipv4() {
+                        return None;
+                    }
+                    for connection in name_server.connections.iter_mut() {
+                        connection.bind_addr = Some(SocketAddr::new(local_address, 0));
+                    }
+                }
+                Some(name_server)
+            })
+            .collect()
+    }
+
+    fn bootstrap_config(&self) -> Result<ResolverConfig, Error> {
+        let config = match &self.bootstrap {
+            BootstrapResolver::Default => ResolverConfig::udp_and_tcp(&GOOGLE),
+            BootstrapResolver::System => hickory_resolver::system_conf::read_system_conf()?.0,
+            BootstrapResolver::Servers(servers) => ResolverConfig::from_parts(
+                None,
+                Vec::new(),
+                servers
+                    .iter()
+                    .map(|ip| NameServerConfig::udp_and_tcp(*ip))
+                    .collect(),
+            ),
+        };
+        Ok(ResolverConfig::from_parts(
+            config.domain().cloned(),
+            config.search().to_vec(),
+            self.bind_name_servers(config.name_servers().iter().cloned()),
+        ))
+    }
+
+    async fn get_upstream(self: &DNSSource, family: Family) -> Result<Upstream, Error> {
+        let family = match (self.local_address, family) {
+            (None, family) => family,
+            (Some(IpAddr::V4(_)), Family::IPv4 | Family::Any) => Family::IPv4,
+            (Some(IpAddr::V6(_)), Family::IPv6 | Family::Any) => Family::IPv6,
+            _ => return Err(Error::UnsupportedFamily),
+        };
+        let provider = BindRuntimeProvider::new(self.interface.clone());
+
+        let mut resolver_opts = self.resolver_opts();
         resolver_opts.ip_strategy = match family {
             Family::IPv4 => LookupIpStrategy::Ipv4Only,
             Family::IPv6 => LookupIpStrategy::Ipv6Only,
             Family::Any => resolver_opts.ip_strategy,
         };
 
-        trace!(
-            "Bootstrapping resolver for {} with strategy {:?}",
-            self.server, resolver_opts.ip_strategy
-        );
-        let mut builder = TokioResolver::builder_with_config(
-            ResolverConfig::udp_and_tcp(&GOOGLE),
-            TokioRuntimeProvider::default(),
-        );
-        *builder.options_mut() = resolver_opts.clone();
-        let resolver = builder.build()?;
+        if self.recursive {
+            let config = self.bootstrap_config()?;
+            let resolver_opts = self.bootstrap_opts(&resolver_opts);
+            return self.upstream(config.name_servers().to_vec(), resolver_opts, provider);
+        }
+        if !self.name_servers.is_empty() {
+            let name_servers: Vec<_> = self
+                .name_servers
+                .iter()
+                .filter(|address| match family {
+                    Family::IPv4 => address.is_ipv4(),
+                    Family::IPv6 => address.is_ipv6(),
+                    Family::Any => true,
+                })
+                .map(|address| {
+                    let mut name_server = self.name_server_config(address.ip());
+                    for connection in name_server.connections.iter_mut() {
+                        connection.port = address.port();
+                    }
+                    name_server
+                })
+                .collect();
+            if name_servers.is_empty() {
+                return Err(Error::UnsupportedFamily);
+            }
+            #[allow(unused_mut)]
+            let mut upstream = self.upstream(name_servers, resolver_opts, provider.clone())?;
+            #[cfg(feature = "dnssec")]
+            {
+                upstream.zone_keys = self.upstream_zone_keys(provider).await?;
+            }
+            return Ok(upstream);
+        }
 
-        let mut name_servers = Vec::new();
-        for found_ip in resolver.lookup_ip(&self.server).await?.iter() {
-            trace!("DNS address {}", found_ip);
-            name_servers.push(NameServerConfig::udp(found_ip));
+        let key = self.upstream_key(family);
+        if let Some((upstream, _)) = cache_get(&UPSTREAMS, &key) {
+            trace!("Reusing resolver for {}", self.server);
+            return Ok(upstream);
         }
+        let (addresses, valid_until) = match cache_get(&NAME_SERVERS, &key.bootstrap) {
+            Some(name_servers) => name_servers,
+            None => {
+                trace!("Bootstrapping resolver for {}", self.server);
+                let bootstrap = self.bootstrap_resolver(provider.clone())?;
+                let lookup = bootstrap.lookup_ip(&self.server).await?;
+                self.check_proof(lookup.as_lookup().answers(), true)?;
+                let addresses: Vec<IpAddr> = lookup.iter().collect();
+                cache_insert(
+                    &NAME_SERVERS,
+                    key.bootstrap.clone(),
+                    addresses.clone(),
+                    lookup.valid_until(),
+                );
+                (addresses, lookup.valid_until())
+            }
+        };
+        let name_servers: Vec<_> = addresses
+            .into_iter()
+            .filter(|ip| match family {
+                Family::IPv4 => ip.is_ipv4(),
+                Family::IPv6 => ip.is_ipv6(),
+                Family::Any => true,
+            })
+            .inspect(|ip| trace!("DNS address {}", ip))
+            .map(|ip| self.name_server_config(ip))
+            .collect();
+        if name_servers.is_empty() {
+            return Err(Error::UnsupportedFamily);
+        }
+        #[allow(unused_mut)]
+        let mut upstream = self.upstream(name_servers, resolver_opts, provider.clone())?;
+        #[cfg(feature = "dnssec")]
+        {
+            upstream.zone_keys = self.upstream_zone_keys(provider).await?;
+        }
+        cache_insert(&UPSTREAMS, key, upstream.clone(), valid_until);
+        Ok(upstream)
+    }
+
+    fn bootstrap_key(&self) -> BootstrapKey {
+        BootstrapKey {
+            server: self.server.clone(),
+            bootstrap: self.bootstrap.clone(),
+            local_address: self.local_address,
+            interface: self.interface.clone(),
+            #[cfg(feature = "dnssec")]
+            dnssec: self.dnssec,
+        }
+    }
+
+    fn upstream_key(&self, family: Family) -> UpstreamKey {
+        UpstreamKey {
+            bootstrap: self.bootstrap_key(),
+            record: self.record.clone(),
+            family,
+            transport: self.transport.clone(),
+            timeout: self.timeout,
+            attempts: self.attempts,
+            edns: self.edns,
+        }
+    }
+
+    /// Drops the cached nameserver addresses of the source, and the resolvers pointing at them
+    fn forget_upstream(&self) {
+        let bootstrap = self.bootstrap_key();
+        NAME_SERVERS.lock().unwrap().remove(&bootstrap);
+        UPSTREAMS
+            .lock()
+            .unwrap()
+            .retain(|key, _| key.bootstrap != bootstrap);
+    }
+
+    /// Checks the DNSSEC proof of the records when validation is enabled, requiring them to be
+    /// secure or only rejecting bogus ones
+    #[cfg(feature = "dnssec")]
+    fn check_proof(&self, records: &[Record], require_secure: bool) -> Result<(), Error> {
+        use hickory_resolver::proto::dnssec::Proof;
+
+        if !self.dnssec {
+            return Ok(());
+        }
+        for record in records {
+            trace!("DNSSEC proof of {}: {}", record.name, record.proof);
+            if record.proof == Proof::Bogus || (require_secure && record.proof != Proof::Secure) {
+                return Err(Error::DnssecValidation(record.name.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "dnssec"))]
+    fn check_proof(&self, _records: &[Record], _require_secure: bool) -> Result<(), Error> {
+        Ok(())
+    }
 
+    /// Builds the resolver querying the given nameservers
+    fn upstream(
+        &self,
+        name_servers: Vec<NameServerConfig>,
+        mut resolver_opts: ResolverOpts,
+        provider: BindRuntimeProvider,
+    ) -> Result<Upstream, Error> {
+        let name_servers = self.bind_name_servers(name_servers);
+        let mut addresses = Vec::new();
+        for server in &name_servers {
+            for connection in &server.connections {
+                let address = SocketAddr::new(server.ip, connection.port);
+                if !addresses.contains(&address) {
+                    addresses.push(address);
+                }
+            }
+        }
         let config = ResolverConfig::from_parts(None, Vec::new(), name_servers);
 
-        let mut builder =
-            TokioResolver::builder_with_config(config, TokioRuntimeProvider::default());
+        // The replies of the nameserver must not be cached, the external IP can change at any time
+        resolver_opts.cache_size = 0;
+        let mut builder = DNSResolver::builder_with_config(config, provider);
         *builder.options_mut() = resolver_opts;
-        Ok(builder.build()?)
+        Ok(Upstream {
+            resolver: builder.build()?,
+            addresses,
+            #[cfg(feature = "dnssec")]
+            zone_keys: Vec::new(),
+        })
     }
 }
 
-impl Source for DNSSource {
-    fn get_ip(&self, family: Family) -> IpFuture<'_> {
-        async fn run(_self: &DNSSource, family: Family) -> IpResult {
-            if matches!(
-                (family, _self.record_type),
-                (Family::IPv4, QueryType::AAAA) | (Family::IPv6, QueryType::A)
-            ) {
-                return Err(Error::UnsupportedFamily);
-            }
-            trace!("Contacting {:?} for {}", _self.server, _self.record);
-            let resolver: TokioResolver = _self
-                .get_resolver(match _self.record_type {
-                    QueryType::A => Family::IPv4,
-                    QueryType::AAAA => Family::IPv6,
-                    _ => family,
+#[cfg(feature = "dnssec")]
+impl DNSSource {
+    /// Returns the keys of the zone of the record when DNSSEC validation is enabled, building
+    /// the bootstrap resolver only then
+    async fn upstream_zone_keys(
+        &self,
+        provider: BindRuntimeProvider,
+    ) -> Result<Vec<DNSKEY>, Error> {
+        if !self.dnssec || matches!(self.record_type, QueryType::ChaosTXT) {
+            return Ok(Vec::new());
+        }
+        self.zone_keys(&self.bootstrap_resolver(provider)?).await
+    }
+
+    /// Returns the keys of the zone of the record when it is signed, looked up through the
+    /// validating bootstrap resolver
+    async fn zone_keys(&self, bootstrap: &DNSResolver) -> Result<Vec<DNSKEY>, Error> {
+        use hickory_resolver::proto::dnssec::Proof;
+        use hickory_resolver::proto::dnssec::rdata::DNSSECRData;
+
+        // The SOA is in the answer at the apex of the zone, and in the authority section below it
+        let soa = match bootstrap.lookup(self.record.clone(), RecordType::SOA).await {
+            Ok(lookup) => lookup
+                .answers()
+                .iter()
+                .find(|record| record.record_type() == RecordType::SOA)
+                .map(|record| (record.name.clone(), record.proof)),
+            Err(err) => err.into_soa().map(|soa| (soa.name.clone(), soa.proof)),
+        };
+        let (zone, proof) = soa.ok_or_else(|| Error::DnssecValidation(self.record.clone()))?;
+        trace!("DNSSEC proof of zone {}: {}", zone, proof);
+        match proof {
+            Proof::Insecure => return Ok(Vec::new()),
+            Proof::Secure => {}
+            _ => return Err(Error::DnssecValidation(zone.to_string())),
+        }
+        let lookup = bootstrap.lookup(zone.clone(), RecordType::DNSKEY).await?;
+        self.check_proof(lookup.answers(), true)?;
+        let keys: Vec<DNSKEY> = lookup
+            .answers()
+            .iter()
+            .filter_map(|record| match &record.data {
+                RData::DNSSEC(DNSSECRData::DNSKEY(key)) if key.zone_key() && !key.revoke() => {
+                    Some(key.clone())
+                }
+                _ => None,
+            })
+            .collect();
+        if keys.is_empty() {
+            return Err(Error::DnssecValidation(zone.to_string()));
+        }
+        Ok(keys)
+    }
+
+    /// Queries the nameserver for the record with the DNSSEC OK bit, and checks the signatures
+    /// of the answers when the zone is signed
+    async fn signed_answers(&self, upstream: &Upstream) -> Result<Option<Vec<Record>>, Error> {
+        let record_type = match self.record_type {
+            _ if upstream.zone_keys.is_empty() => return Ok(None),
+            QueryType::TXT => RecordType::TXT,
+            QueryType::A => RecordType::A,
+            QueryType::AAAA => RecordType::AAAA,
+            QueryType::ChaosTXT => return Ok(None),
+        };
+        let name = Name::from_ascii(&self.record).map_err(NetError::from)?;
+        let answers = self
+            .direct_lookup(upstream, Query::query(name, record_type), true)
+            .await?;
+        verify_answers(&answers, record_type, &upstream.zone_keys)?;
+        Ok(Some(answers))
+    }
+}
+
+#[cfg(not(feature = "dnssec"))]
+impl DNSSource {
+    async fn signed_answers(&self, _upstream: &Upstream) -> Result<Option<Vec<Record>>, Error> {
+        Ok(None)
+    }
+}
+
+/// Checks that every set of answers of the record type is signed by one of the zone keys
+#[cfg(feature = "dnssec")]
+fn verify_answers(
+    answers: &[Record],
+    record_type: RecordType,
+    keys: &[DNSKEY],
+) -> Result<(), Error> {
+    use hickory_resolver::proto::dnssec::Verifier;
+    use hickory_resolver::proto::dnssec::rdata::DNSSECRData;
+    use hickory_resolver::proto::rr::SerialNumber;
+
+    let now = SerialNumber::new(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32,
+    );
+    let mut names: Vec<&Name> = Vec::new();
+    for record in answers.iter().filter(|r| r.record_type() == record_type) {
+        if !names.contains(&&record.name) {
+            names.push(&record.name);
+        }
+    }
+    for name in names {
+        let rrset = answers
+            .iter()
+            .filter(|r| r.record_type() == record_type && &r.name == name);
+        let verified = answers.iter().any(|record| {
+            let RData::DNSSEC(DNSSECRData::RRSIG(rrsig)) = &record.data else {
+                return false;
+            };
+            let input = rrsig.input();
+            &record.name == name
+                && input.type_covered == record_type
+                && input.sig_inception <= now
+                && now <= input.sig_expiration
+                && keys.iter().any(|key| {
+                    key.verify_rrsig(name, DNSClass::IN, rrsig, rrset.clone())
+                        .is_ok()
                 })
-                .await?;
-
-            match _self.record_type {
-                QueryType::TXT => {
-                    for reply in resolver.txt_lookup(_self.record.clone()).await?.answers() {
-                        if let RData::TXT(txt) = &reply.data {
-                            for txt in txt.txt_data.iter() {
-                                if let Ok(data) = std::str::from_utf8(txt) {
-                                    let ip: std::net::IpAddr = data.parse()?;
-                                    if family == Family::Any
-                                        || (family == Family::IPv4 && ip.is_ipv4())
-                                        || (family == Family::IPv6 && ip.is_ipv6())
-                                    {
-                                        return Ok(ip);
-                                    }
-                                }
-                            }
+        });
+        if !verified {
+            trace!("No valid signature for {} {}", name, record_type);
+            return Err(Error::DnssecValidation(name.to_string()));
+        }
+    }
+    Ok(())
+}
+
+impl DNSSource {
+    /// Sends a CHAOS class TXT query to the nameserver addresses, returning the first reply
+    async fn chaos_txt_lookup(&self, upstream: &Upstream) -> Result<Vec<Record>, Error> {
+        let name = Name::from_ascii(&self.record).map_err(NetError::from)?;
+        let mut query = Query::query(name, RecordType::TXT);
+        query.set_query_class(DNSClass::CH);
+        self.direct_lookup(upstream, query, false).await
+    }
+
+    /// Sends the query to the nameserver addresses without going through the resolver, returning
+    /// the answers of the first reply
+    async fn direct_lookup(
+        &self,
+        upstream: &Upstream,
+        query: Query,
+        dnssec_ok: bool,
+    ) -> Result<Vec<Record>, Error> {
+        let resolver_opts = self.resolver_opts();
+        let mut last_error = Error::DnsResolutionEmpty;
+        for _ in 0..=resolver_opts.attempts {
+            for address in &upstream.addresses {
+                let (query, address) = (query.clone(), *address);
+                let reply = match &self.transport {
+                    Transport::Udp => self.direct_udp(address, query, dnssec_ok).await,
+                    Transport::Tcp => self.direct_tcp(address, query, dnssec_ok).await,
+                    Transport::UdpAndTcp => {
+                        match self.direct_udp(address, query.clone(), dnssec_ok).await {
+                            Ok(reply) if !reply.metadata.truncation => Ok(reply),
+                            _ => self.direct_tcp(address, query, dnssec_ok).await,
                         }
                     }
-                }
-                QueryType::A => {
-                    if family == Family::IPv4 || family == Family::Any {
-                        for reply in resolver.lookup_ip(_self.record.clone()).await?.iter() {
-                            if reply.is_ipv4() {
-                                return Ok(reply);
-                            }
-                        }
+                    Transport::Tls { .. } => self.direct_tls(address, query, dnssec_ok).await,
+                };
+                match reply {
+                    Ok(reply) => return Ok(reply.answers.clone()),
+                    Err(err) => {
+                        trace!("Query to {} failed: {}", address, err);
+                        last_error = Error::Dns(err);
                     }
-                    return Err(Error::UnsupportedFamily);
                 }
-                QueryType::AAAA => {
-                    if family == Family::IPv6 || family == Family::Any {
-                        for reply in resolver.lookup_ip(_self.record.clone()).await?.iter() {
-                            if reply.is_ipv6() {
-                                return Ok(reply);
-                            }
-                        }
+            }
+        }
+        Err(last_error)
+    }
+
+    fn bind_addr(&self) -> Option<SocketAddr> {
+        self.local_address.map(|local| SocketAddr::new(local, 0))
+    }
+
+    fn query_timeout(&self) -> Duration {
+        self.resolver_opts().timeout
+    }
+
+    async fn direct_udp(
+        &self,
+        address: SocketAddr,
+        query: Query,
+        dnssec_ok: bool,
+    ) -> Result<DnsResponse, NetError> {
+        let provider = BindRuntimeProvider::new(self.interface.clone());
+        let stream = UdpClientStream::builder(address, provider)
+            .with_bind_addr(self.bind_addr())
+            .with_timeout(Some(self.query_timeout()))
+            .build();
+        send_query(stream, query, self.edns, dnssec_ok).await
+    }
+
+    async fn direct_tcp(
+        &self,
+        address: SocketAddr,
+        query: Query,
+        dnssec_ok: bool,
+    ) -> Result<DnsResponse, NetError> {
+        let provider = BindRuntimeProvider::new(self.interface.clone());
+        let (stream, handle) = TcpClientStream::new(
+            address,
+            self.bind_addr(),
+            Some(self.query_timeout()),
+            provider,
+        );
+        let multiplexer =
+            DnsMultiplexer::new(stream.await?, handle).with_timeout(self.query_timeout());
+        send_query(multiplexer, query, self.edns, dnssec_ok).await
+    }
+
+    async fn direct_tls(
+        &self,
+        address: SocketAddr,
+        query: Query,
+        dnssec_ok: bool,
+    ) -> Result<DnsResponse, NetError> {
+        let provider = BindRuntimeProvider::new(self.interface.clone());
+        let server_name = ServerName::try_from(self.tls_name().to_string())
+            .map_err(|err| NetError::from(err.to_string()))?;
+        let (stream, handle) = tls_client_connect_with_bind_addr(
+            address,
+            self.bind_addr(),
+            server_name,
+            Arc::new(client_config().map_err(|err| NetError::from(err.to_string()))?),
+            provider,
+        );
+        let multiplexer =
+            DnsMultiplexer::new(stream.await?, handle).with_timeout(self.query_timeout());
+        send_query(multiplexer, query, self.edns, dnssec_ok).await
+    }
+
+    async fn query(&self, upstream: &Upstream, family: Family) -> Result<Vec<IpAddr>, Error> {
+        let resolver = &upstream.resolver;
+        let ips = match self.record_type {
+            QueryType::TXT => {
+                let answers = match self.signed_answers(upstream).await? {
+                    Some(answers) => answers,
+                    None => {
+                        let lookup = resolver.txt_lookup(self.record.clone()).await?;
+                        self.check_proof(lookup.answers(), false)?;
+                        lookup.answers().to_vec()
                     }
+                };
+                txt_addresses(&answers, family, &self.extractor)
+            }
+            QueryType::ChaosTXT => {
+                let answers = self.chaos_txt_lookup(upstream).await?;
+                txt_addresses(&answers, family, &self.extractor)
+            }
+            QueryType::A | QueryType::AAAA => {
+                let ipv4 = matches!(self.record_type, QueryType::A);
+                let ips: Vec<IpAddr> = match self.signed_answers(upstream).await? {
+                    Some(answers) => answer_addresses(&answers, Family::Any),
+                    None => {
+                        let lookup = resolver.lookup_ip(self.record.clone()).await?;
+                        self.check_proof(lookup.as_lookup().answers(), false)?;
+                        lookup.iter().collect()
+                    }
+                };
+                let ips: Vec<IpAddr> = ips.into_iter().filter(|ip| ip.is_ipv4() == ipv4).collect();
+                if ips.is_empty() {
                     return Err(Error::UnsupportedFamily);
                 }
+                ips
+            }
+        };
+        if ips.is_empty() {
+            return Err(Error::DnsResolutionEmpty);
+        }
+        Ok(ips)
+    }
+
+    /// Returns every candidate address found in the reply of the nameserver
+    pub async fn get_ips(&self, family: Family) -> Result<Vec<IpAddr>, Error> {
+        if matches!(
+            (family, self.record_type),
+            (Family::IPv4, QueryType::AAAA) | (Family::IPv6, QueryType::A)
+        ) {
+            return Err(Error::UnsupportedFamily);
+        }
+        trace!("Contacting {:?} for {}", self.server, self.record);
+        let upstream = self
+            .get_upstream(match self.record_type {
+                QueryType::A => Family::IPv4,
+                QueryType::AAAA => Family::IPv6,
+                _ => family,
+            })
+            .await?;
+
+        let result = self.query(&upstream, family).await;
+        if matches!(result, Err(Error::Dns(_))) {
+            // The nameserver might have moved, bootstrap again on the next lookup
+            self.forget_upstream();
+        }
+        result
+    }
+}
+
+/// Sends a single query over the given connection, setting the DNSSEC OK bit if asked
+async fn send_query<S: DnsRequestSender>(
+    sender: S,
+    query: Query,
+    edns: Option<bool>,
+    dnssec_ok: bool,
+) -> Result<DnsResponse, NetError> {
+    let (client, background) = Client::<BindRuntimeProvider>::from_sender(sender);
+    let mut options = DnsRequestOptions::default();
+    options.use_edns = edns.unwrap_or(client.is_using_edns()) || dnssec_ok;
+    options.edns_set_dnssec_ok = dnssec_ok;
+    let background = tokio::spawn(background);
+    let reply = client.lookup(query, options).first_answer().await;
+    background.abort();
+    reply
+}
+
+/// Returns the addresses of the family found in the A and AAAA records
+pub(crate) fn answer_addresses<'a, I>(records: I, family: Family) -> Vec<IpAddr>
+where
+    I: IntoIterator<Item = &'a Record>,
+{
+    records
+        .into_iter()
+        .filter_map(|record| match record.data {
+            RData::A(ip) => Some(IpAddr::V4(ip.0)),
+            RData::AAAA(ip) => Some(IpAddr::V6(ip.0)),
+            _ => None,
+        })
+        .filter(|ip| {
+            family == Family::Any
+                || (family == Family::IPv4 && ip.is_ipv4())
+                || (family == Family::IPv6 && ip.is_ipv6())
+        })
+        .collect()
+}
+
+/// Returns the addresses of the family found in the TXT records, skipping strings that are not
+/// addresses
+pub(crate) fn txt_addresses<'a, I>(
+    records: I,
+    family: Family,
+    extractor: &TxtExtractor,
+) -> Vec<IpAddr>
+where
+    I: IntoIterator<Item = &'a Record>,
+{
+    let mut ips = Vec::new();
+    for reply in records {
+        if let RData::TXT(txt) = &reply.data {
+            for txt in txt.txt_data.iter() {
+                let Ok(data) = std::str::from_utf8(txt) else {
+                    continue;
+                };
+                let Some(ip) = extractor.extract(data) else {
+                    trace!("Skipping TXT string {:?}", data);
+                    continue;
+                };
+                if (family == Family::Any
+                    || (family == Family::IPv4 && ip.is_ipv4())
+                    || (family == Family::IPv6 && ip.is_ipv6()))
+                    && !ips.contains(&ip)
+                {
+                    ips.push(ip);
+                }
             }
-            Err(Error::DnsResolutionEmpty)
+        }
+    }
+    ips
+}
+
+impl Source for DNSSource {
+    fn get_ip(&self, family: Family) -> IpFuture<'_> {
+        async fn run(_self: &DNSSource, family: Family) -> IpResult {
+            let ips = _self.get_ips(family).await?;
+            ips.into_iter().next().ok_or(Error::DnsResolutionEmpty)
         }
         Box::pin(run(self, family))
     }
@@ -164,6 +1080,15 @@ impl Source for DNSSource {
 
 /// Returns a collection of DNS sources to use to retrieve the external ip
 pub fn get_dns_sources<T>() -> T
+where
+    T: std::iter::FromIterator<Box<dyn Source>>,
+{
+    get_dns_sources_with_bootstrap(BootstrapResolver::default())
+}
+
+/// Returns a collection of DNS sources to use to retrieve the external ip, looking up their
+/// nameservers with the given bootstrap resolver
+pub fn get_dns_sources_with_bootstrap<T>(bootstrap: BootstrapResolver) -> T
 where
     T: std::iter::FromIterator<Box<dyn Source>>,
 {
@@ -172,18 +1097,523 @@ where
             String::from("resolver1.opendns.com"),
             QueryType::A,
             "myip.opendns.com",
+            &bootstrap,
         ),
         DNSSource::source(
             String::from("resolver1.opendns.com"),
             QueryType::AAAA,
             "myip.opendns.com",
+            &bootstrap,
         ),
         DNSSource::source(
             String::from("ns1.google.com"),
             QueryType::TXT,
             "o-o.myaddr.l.google.com",
+            &bootstrap,
+        ),
+        DNSSource::name_server_source(
+            IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
+            QueryType::ChaosTXT,
+            "whoami.cloudflare",
+        ),
+        DNSSource::name_server_source(
+            IpAddr::V6(Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111)),
+            QueryType::ChaosTXT,
+            "whoami.cloudflare",
+        ),
+        DoHSource::source(
+            "https://cloudflare-dns.com/dns-query",
+            QueryType::ChaosTXT,
+            "whoami.cloudflare",
         ),
     ]
     .into_iter()
     .collect()
 }
+
+/// Returns a collection of DNS sources reporting the public address of the recursive resolver
+/// configured in the system, instead of the external ip
+///
+/// Useful to check that DNS queries leave through the expected network (e.g. a VPN tunnel).
+pub fn get_resolver_egress_sources<T>() -> T
+where
+    T: std::iter::FromIterator<Box<dyn Source>>,
+{
+    [
+        (QueryType::A, "whoami.akamai.net"),
+        (QueryType::TXT, "o-o.myaddr.l.google.com"),
+    ]
+    .into_iter()
+    .map(|(record_type, record)| -> Box<dyn Source> {
+        Box::new(
+            DNSSourceBuilder::new("system resolver", record_type, record)
+                .with_bootstrap(BootstrapResolver::System)
+                .with_recursive_query()
+                .build(),
+        )
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::sources::test_util::serve_udp;
+    use hickory_resolver::proto::op::{Message, Query};
+    use hickory_resolver::proto::rr::rdata::TXT;
+    use std::net::{Ipv4Addr, Ipv6Addr, UdpSocket};
+    use tokio_test::block_on;
+
+    /// Runs a stand-in DNS server on a loopback port answering with the records returned by
+    /// `answer`
+    fn serve<F>(answer: F) -> SocketAddr
+    where
+        F: Fn(&Query) -> Vec<Record> + Send + 'static,
+    {
+        serve_on(IpAddr::V4(Ipv4Addr::LOCALHOST), answer)
+    }
+
+    fn serve_on<F>(ip: IpAddr, answer: F) -> SocketAddr
+    where
+        F: Fn(&Query) -> Vec<Record> + Send + 'static,
+    {
+        serve_udp(ip, move |request| {
+            let request = Message::from_vec(request).ok()?;
+            let mut response = Message::response(request.metadata.id, request.metadata.op_code);
+            for query in &request.queries {
+                response.add_query(query.clone());
+                for record in answer(query) {
+                    response.add_answer(record);
+                }
+            }
+            Some(response.to_vec().expect("encode"))
+        })
+    }
+
+    fn txt_record(query: &Query, text: &str) -> Record {
+        let mut record = Record::from_rdata(
+            query.name().clone(),
+            0,
+            RData::TXT(TXT::new(vec![text.to_string()])),
+        );
+        record.dns_class = query.query_class();
+        record
+    }
+
+    #[test]
+    fn test_local_address_family() {
+        let source =
+            DNSSourceBuilder::new("resolver1.opendns.com", QueryType::AAAA, "myip.opendns.com")
+                .with_local_address(IpAddr::V4(Ipv4Addr::LOCALHOST))
+                .build();
+        assert!(matches!(
+            block_on(source.get_ip(Family::Any)),
+            Err(Error::UnsupportedFamily)
+        ));
+    }
+
+    #[test]
+    fn test_bootstrap_servers() {
+        let source =
+            DNSSourceBuilder::new("resolver1.opendns.com", QueryType::A, "myip.opendns.com")
+                .with_bootstrap(BootstrapResolver::Servers(vec![
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    "::1".parse().unwrap(),
+                ]))
+                .with_local_address(IpAddr::V4(Ipv4Addr::LOCALHOST))
+                .build();
+        let config = source.bootstrap_config().expect("valid configuration");
+        let name_servers = config.name_servers();
+        assert_eq!(name_servers.len(), 1);
+        assert_eq!(name_servers[0].ip, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert!(
+            name_servers[0]
+                .connections
+                .iter()
+                .all(|c| c.bind_addr == Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)))
+        );
+    }
+
+    #[test]
+    fn test_resolver_cache() {
+        // The cache is shared by every source, the server is only used by this test
+        let ipv4 = DNSSource::new("cache.example.test", QueryType::A, "myip.example.test");
+        let ipv6 = DNSSource::new("cache.example.test", QueryType::AAAA, "myip.example.test");
+        let addresses = vec![
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ];
+        let later = Instant::now() + Duration::from_secs(60);
+        cache_insert(&NAME_SERVERS, ipv4.bootstrap_key(), addresses, later);
+
+        // Both families reuse the bootstrapped addresses, without looking them up again
+        let upstream = block_on(ipv4.get_upstream(Family::IPv4)).expect("cached addresses");
+        assert_eq!(
+            upstream.addresses,
+            vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 53)]
+        );
+        let upstream = block_on(ipv6.get_upstream(Family::IPv6)).expect("cached addresses");
+        assert_eq!(
+            upstream.addresses,
+            vec![SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 53)]
+        );
+
+        // A source built later gets the resolver of the earlier one
+        let fresh = DNSSource::new("cache.example.test", QueryType::A, "myip.example.test");
+        assert!(cache_get(&UPSTREAMS, &fresh.upstream_key(Family::IPv4)).is_some());
+        let other = DNSSourceBuilder::new("cache.example.test", QueryType::A, "myip.example.test")
+            .with_transport(Transport::Tcp)
+            .build();
+        assert!(cache_get(&UPSTREAMS, &other.upstream_key(Family::IPv4)).is_none());
+
+        fresh.forget_upstream();
+        assert!(cache_get(&NAME_SERVERS, &ipv6.bootstrap_key()).is_none());
+        assert!(cache_get(&UPSTREAMS, &ipv6.upstream_key(Family::IPv6)).is_none());
+
+        cache_insert(
+            &NAME_SERVERS,
+            ipv4.bootstrap_key(),
+            Vec::new(),
+            Instant::now(),
+        );
+        assert!(cache_get(&NAME_SERVERS, &ipv4.bootstrap_key()).is_none());
+    }
+
+    #[test]
+    fn test_chaos_txt() {
+        let address = serve(|query| {
+            if query.query_class() == DNSClass::CH && query.query_type() == RecordType::TXT {
+                vec![txt_record(query, "1.2.3.4")]
+            } else {
+                Vec::new()
+            }
+        });
+        let source = DNSSourceBuilder::new("1.1.1.1", QueryType::ChaosTXT, "whoami.cloudflare")
+            .with_name_servers([address])
+            .build();
+        let ip = block_on(source.get_ip(Family::Any)).expect("valid reply");
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+    }
+
+    #[test]
+    fn test_name_servers() {
+        let address = serve(|query| {
+            if query.query_class() == DNSClass::IN && query.query_type() == RecordType::TXT {
+                vec![txt_record(query, "1.2.3.4")]
+            } else {
+                Vec::new()
+            }
+        });
+        let source =
+            DNSSourceBuilder::new("ns1.google.com", QueryType::TXT, "o-o.myaddr.l.google.com")
+                .with_name_servers([address])
+                .build();
+        let ip = block_on(source.get_ip(Family::IPv4)).expect("valid reply");
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+        assert!(matches!(
+            block_on(source.get_ip(Family::IPv6)),
+            Err(Error::UnsupportedFamily)
+        ));
+    }
+
+    #[test]
+    fn test_name_servers_ipv6() {
+        // Hosts without an IPv6 loopback can't run the stand-in nameserver
+        if UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).is_err() {
+            return;
+        }
+        let address = serve_on(IpAddr::V6(Ipv6Addr::LOCALHOST), |query| {
+            if query.query_class() == DNSClass::IN && query.query_type() == RecordType::TXT {
+                vec![txt_record(query, "2001:db8::1")]
+            } else {
+                Vec::new()
+            }
+        });
+        let source =
+            DNSSourceBuilder::new("ns1.google.com", QueryType::TXT, "o-o.myaddr.l.google.com")
+                .with_name_servers([address])
+                .build();
+        let ip = block_on(source.get_ip(Family::IPv6)).expect("valid reply");
+        assert_eq!(ip, "2001:db8::1".parse::<IpAddr>().unwrap());
+        assert!(matches!(
+            block_on(source.get_ip(Family::IPv4)),
+            Err(Error::UnsupportedFamily)
+        ));
+    }
+
+    #[cfg(feature = "dnssec")]
+    #[test]
+    fn test_dnssec_proof() {
+        use hickory_resolver::proto::dnssec::Proof;
+
+        let query = Query::query(
+            Name::from_ascii("ns1.google.com.").unwrap(),
+            RecordType::TXT,
+        );
+        let mut record = txt_record(&query, "1.2.3.4");
+        let builder =
+            DNSSourceBuilder::new("ns1.google.com", QueryType::TXT, "o-o.myaddr.l.google.com");
+        let source = builder.with_dnssec(true).build();
+
+        record.proof = Proof::Insecure;
+        assert!(source.check_proof(&[record.clone()], false).is_ok());
+        assert!(matches!(
+            source.check_proof(&[record.clone()], true),
+            Err(Error::DnssecValidation(_))
+        ));
+        record.proof = Proof::Bogus;
+        assert!(source.check_proof(&[record.clone()], false).is_err());
+        record.proof = Proof::Secure;
+        assert!(source.check_proof(&[record], true).is_ok());
+    }
+
+    /// Signs the TXT record of the query with a new zone key, returning the key and the record
+    /// followed by its RRSIG, with `text` replacing the signed data when given
+    #[cfg(feature = "dnssec")]
+    fn signed_txt(query: &Query, signed: &str, text: &str) -> (DNSKEY, Vec<Record>) {
+        use hickory_resolver::proto::dnssec::crypto::Ed25519SigningKey;
+        use hickory_resolver::proto::dnssec::rdata::{DNSSECRData, RRSIG, SigInput};
+        use hickory_resolver::proto::dnssec::{Algorithm, SigningKey, TBS};
+        use hickory_resolver::proto::rr::SerialNumber;
+
+        let pkcs8 = Ed25519SigningKey::generate_pkcs8().expect("key");
+        let signing_key = Ed25519SigningKey::from_pkcs8(&pkcs8).expect("key");
+        let key = DNSKEY::new(true, true, false, signing_key.to_public_key().expect("key"));
+        let mut record = txt_record(query, signed);
+        record.dns_class = DNSClass::IN;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+        let input = SigInput {
+            type_covered: RecordType::TXT,
+            algorithm: Algorithm::ED25519,
+            num_labels: query.name().num_labels(),
+            original_ttl: record.ttl,
+            sig_expiration: SerialNumber::new(now + 300),
+            sig_inception: SerialNumber::new(now - 300),
+            key_tag: 0,
+            signer_name: query.name().base_name(),
+        };
+        let tbs = TBS::from_input(query.name(), DNSClass::IN, &input, [&record].into_iter())
+            .expect("tbs");
+        let sig = signing_key.sign(&tbs).expect("signature");
+        let rrsig = Record::from_rdata(
+            query.name().clone(),
+            0,
+            RData::DNSSEC(DNSSECRData::RRSIG(RRSIG::from_sig(input, sig))),
+        );
+        let mut record = txt_record(query, text);
+        record.dns_class = DNSClass::IN;
+        (key, vec![record, rrsig])
+    }
+
+    #[cfg(feature = "dnssec")]
+    #[test]
+    fn test_dnssec_signed_zone() {
+        let name = Name::from_ascii("o-o.myaddr.l.example.com.").unwrap();
+        let query = Query::query(name, RecordType::TXT);
+        for (text, valid) in [("1.2.3.4", true), ("5.6.7.8", false)] {
+            let (key, records) = signed_txt(&query, "1.2.3.4", text);
+            let address = serve(move |query| {
+                if query.query_type() == RecordType::TXT {
+                    records.clone()
+                } else {
+                    Vec::new()
+                }
+            });
+            let source = DNSSourceBuilder::new(
+                "ns1.example.com",
+                QueryType::TXT,
+                "o-o.myaddr.l.example.com",
+            )
+            .with_dnssec(true)
+            .build();
+            let provider = BindRuntimeProvider::default();
+            let mut name_server = source.name_server_config(address.ip());
+            for connection in name_server.connections.iter_mut() {
+                connection.port = address.port();
+            }
+            let mut upstream = source
+                .upstream(vec![name_server], source.resolver_opts(), provider)
+                .expect("valid upstream");
+
+            // Unsigned zones are queried through the resolver, the stand-in replies without any
+            // signature checked
+            assert!(block_on(source.query(&upstream, Family::Any)).is_ok());
+
+            upstream.zone_keys = vec![key];
+            let result = block_on(source.query(&upstream, Family::Any));
+            if valid {
+                assert_eq!(result.unwrap(), vec![IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))]);
+            } else {
+                assert!(matches!(result, Err(Error::DnssecValidation(_))));
+            }
+        }
+    }
+
+    #[cfg(feature = "dnssec")]
+    #[test]
+    fn test_dnssec_bootstrap_unproven() {
+        // The stand-in resolver answers without signatures, the zone of the record can't be
+        // proven signed or unsigned
+        let address = serve(|query| {
+            let soa = hickory_resolver::proto::rr::rdata::SOA::new(
+                Name::from_ascii("ns1.example.com.").unwrap(),
+                Name::from_ascii("admin.example.com.").unwrap(),
+                1,
+                3600,
+                600,
+                86400,
+                60,
+            );
+            if query.query_type() == RecordType::SOA {
+                vec![Record::from_rdata(
+                    Name::from_ascii("example.com.").unwrap(),
+                    60,
+                    RData::SOA(soa),
+                )]
+            } else {
+                Vec::new()
+            }
+        });
+        let source = DNSSourceBuilder::new("ns1.example.com", QueryType::A, "myip.example.com")
+            .with_dnssec(true)
+            .build();
+        let mut name_server = NameServerConfig::udp(address.ip());
+        for connection in name_server.connections.iter_mut() {
+            connection.port = address.port();
+        }
+        let config = ResolverConfig::from_parts(None, Vec::new(), vec![name_server]);
+        let resolver_opts = source.resolver_opts();
+        assert!(!resolver_opts.validate);
+        let mut builder = DNSResolver::builder_with_config(config, BindRuntimeProvider::default());
+        *builder.options_mut() = source.bootstrap_opts(&resolver_opts);
+        assert!(builder.options_mut().validate);
+        let bootstrap = builder.build().expect("valid resolver");
+
+        let result = block_on(source.zone_keys(&bootstrap));
+        assert!(
+            matches!(result, Err(Error::DnssecValidation(_))),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn test_recursive_query() {
+        let source = DNSSourceBuilder::new("system resolver", QueryType::A, "whoami.akamai.net")
+            .with_bootstrap(BootstrapResolver::Servers(vec![IpAddr::V4(
+                Ipv4Addr::LOCALHOST,
+            )]))
+            .with_recursive_query()
+            .build();
+        let upstream = block_on(source.get_upstream(Family::IPv4)).expect("valid upstream");
+        assert_eq!(
+            upstream.addresses,
+            vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 53)]
+        );
+    }
+
+    #[test]
+    fn test_tls_transport() {
+        let ip = IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8));
+        let builder =
+            DNSSourceBuilder::new("dns.google", QueryType::TXT, "o-o.myaddr.l.google.com");
+        let source = builder
+            .with_transport(Transport::Tls { server_name: None })
+            .build();
+        let config = source.name_server_config(ip);
+        assert_eq!(config.connections.len(), 1);
+        assert_eq!(config.connections[0].port, 853);
+        assert!(matches!(
+            &config.connections[0].protocol,
+            ProtocolConfig::Tls { server_name } if &**server_name == "dns.google"
+        ));
+
+        let source = DNSSourceBuilder::new("dns.google", QueryType::TXT, "o-o.myaddr.l.google.com")
+            .with_transport(Transport::Tls {
+                server_name: Some(String::from("google.dns")),
+            })
+            .build();
+        assert_eq!(source.tls_name(), "google.dns");
+    }
+
+    #[test]
+    fn test_txt_skips_invalid_strings() {
+        let address = serve(|query| {
+            vec![
+                txt_record(query, "edns0-client-subnet 1.2.3.0/24"),
+                txt_record(query, "1.2.3.4"),
+                txt_record(query, "2001:db8::1"),
+            ]
+        });
+        let source = DNSSourceBuilder::new("1.1.1.1", QueryType::ChaosTXT, "whoami.cloudflare")
+            .with_name_servers([address])
+            .build();
+        let ips = block_on(source.get_ips(Family::Any)).expect("valid reply");
+        assert_eq!(
+            ips,
+            vec![
+                IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
+                "2001:db8::1".parse::<IpAddr>().unwrap()
+            ]
+        );
+        let ip = block_on(source.get_ip(Family::Any)).expect("valid reply");
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+    }
+
+    #[test]
+    fn test_txt_extractors() {
+        let ip = Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+        assert_eq!(TxtExtractor::Address.extract(" 1.2.3.4 "), ip);
+        assert_eq!(TxtExtractor::Address.extract("ip=1.2.3.4"), None);
+        assert_eq!(
+            TxtExtractor::Prefix(String::from("ip=")).extract("ip=1.2.3.4"),
+            ip
+        );
+        let regex = Regex::new(r"client-subnet (\S+)/\d+").unwrap();
+        assert_eq!(
+            TxtExtractor::Regex(regex).extract("edns0-client-subnet 1.2.3.4/24"),
+            ip
+        );
+        let custom = TxtExtractor::custom(|data| data.split(',').nth(1)?.parse().ok());
+        assert_eq!(custom.extract("a,1.2.3.4"), ip);
+    }
+
+    #[test]
+    fn test_builder_options() {
+        let ip = IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8));
+        let source =
+            DNSSourceBuilder::new("ns1.google.com", QueryType::TXT, "o-o.myaddr.l.google.com")
+                .with_transport(Transport::Tcp)
+                .with_timeout(Duration::from_secs(1))
+                .with_attempts(0)
+                .with_edns(true)
+                .build();
+        let config = source.name_server_config(ip);
+        assert_eq!(config.connections.len(), 1);
+        assert!(matches!(
+            config.connections[0].protocol,
+            ProtocolConfig::Tcp
+        ));
+
+        let resolver_opts = source.resolver_opts();
+        assert_eq!(resolver_opts.timeout, Duration::from_secs(1));
+        assert_eq!(resolver_opts.attempts, 0);
+        assert!(resolver_opts.edns0);
+    }
+
+    #[test]
+    fn test_chaos_txt_udp_and_tcp() {
+        let address = serve(|query| vec![txt_record(query, "1.2.3.4")]);
+        let source = DNSSourceBuilder::new("1.1.1.1", QueryType::ChaosTXT, "whoami.cloudflare")
+            .with_transport(Transport::UdpAndTcp)
+            .with_timeout(Duration::from_secs(1))
+            .with_edns(false)
+            .with_name_servers([address])
+            .build();
+        let ip = block_on(source.get_ip(Family::Any)).expect("valid reply");
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+    }
+}
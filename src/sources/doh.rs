@@ -0,0 +1,195 @@
+use crate::sources::dns::{QueryType, TxtExtractor, answer_addresses, txt_addresses};
+use crate::sources::http::{check_content_type, client_builder, read_body};
+use crate::sources::interfaces::{Error, Family, IpFuture, IpResult, Source};
+use log::trace;
+use std::time::Duration;
+
+use hickory_resolver::net::NetError;
+use hickory_resolver::proto::op::{Message, Query};
+use hickory_resolver::proto::rr::{DNSClass, Name, RecordType};
+
+/// Media type of DNS messages in wire format (RFC 8484)
+const DNS_MESSAGE: &str = "application/dns-message";
+/// Largest DNS message, the limit on the size of the reply
+const MAX_MESSAGE_SIZE: usize = 65535;
+
+/// DNS over HTTPS Source of the external ip
+///
+/// It sends the DNS query in wire format (RFC 8484) to the given DoH endpoint, to retrive in the
+/// reply the IP. The connection is made over the requested family, so reflectors replying with the
+/// address of the DNS client (e.g. `whoami.cloudflare`) report the external IP.
+///
+/// Replies not typed `application/dns-message`, or larger than a DNS message can be, are rejected.
+#[derive(Debug, Clone)]
+pub struct DoHSource {
+    url: String,
+    record_type: QueryType,
+    record: String,
+    timeout: Duration,
+}
+
+impl DoHSource {
+    pub fn new<S: Into<String>, R: Into<String>>(
+        url: S,
+        record_type: QueryType,
+        record: R,
+    ) -> Self {
+        DoHSource {
+            url: url.into(),
+            record_type,
+            record: record.into(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    pub(crate) fn source<S: Into<String>, R: Into<String>>(
+        url: S,
+        record_type: QueryType,
+        record: R,
+    ) -> Box<dyn Source> {
+        Box::new(DoHSource::new(url, record_type, record))
+    }
+
+    fn request(&self) -> Result<Vec<u8>, Error> {
+        let (class, record_type) = match self.record_type {
+            QueryType::A => (DNSClass::IN, RecordType::A),
+            QueryType::AAAA => (DNSClass::IN, RecordType::AAAA),
+            QueryType::TXT => (DNSClass::IN, RecordType::TXT),
+            QueryType::ChaosTXT => (DNSClass::CH, RecordType::TXT),
+        };
+        let name = Name::from_ascii(&self.record).map_err(NetError::from)?;
+        let mut query = Query::query(name, record_type);
+        query.set_query_class(class);
+
+        // The message ID should be 0 to make the request cache friendly (RFC 8484 section 4.1)
+        let mut message = Message::query();
+        message.metadata.id = 0;
+        message.metadata.recursion_desired = true;
+        message.add_query(query);
+        Ok(message.to_vec().map_err(NetError::from)?)
+    }
+}
+
+impl std::fmt::Display for DoHSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "DoHSource: {} {:?} {}",
+            self.url, self.record_type, self.record
+        )
+    }
+}
+
+impl Source for DoHSource {
+    fn get_ip(&self, family: Family) -> IpFuture<'_> {
+        async fn run(_self: &DoHSource, family: Family) -> IpResult {
+            if matches!(
+                (family, _self.record_type),
+                (Family::IPv4, QueryType::AAAA) | (Family::IPv6, QueryType::A)
+            ) {
+                return Err(Error::UnsupportedFamily);
+            }
+
+            trace!("Contacting {:?} for {}", _self.url, _self.record);
+            let client = client_builder(_self.timeout, None, family)?.build()?;
+            let resp = client
+                .post(&_self.url)
+                .header(reqwest::header::CONTENT_TYPE, DNS_MESSAGE)
+                .header(reqwest::header::ACCEPT, DNS_MESSAGE)
+                .body(_self.request()?)
+                .send()
+                .await?;
+            if !resp.status().is_success() {
+                return Err(Error::HttpStatus(resp.status()));
+            }
+            check_content_type(&resp, &[String::from(DNS_MESSAGE)])?;
+            let body = read_body(resp, MAX_MESSAGE_SIZE).await?;
+            let reply = Message::from_vec(&body).map_err(NetError::from)?;
+
+            let ips = match _self.record_type {
+                QueryType::TXT | QueryType::ChaosTXT => {
+                    txt_addresses(&reply.answers, family, &TxtExtractor::default())
+                }
+                QueryType::A | QueryType::AAAA => answer_addresses(&reply.answers, family),
+            };
+            ips.into_iter().next().ok_or(Error::DnsResolutionEmpty)
+        }
+        Box::pin(run(self, family))
+    }
+
+    fn box_clone(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::sources::test_util::{response, serve_http};
+    use hickory_resolver::proto::rr::rdata::TXT;
+    use hickory_resolver::proto::rr::{RData, Record};
+    use std::net::{IpAddr, Ipv4Addr};
+    use tokio_test::block_on;
+
+    /// Serves DoH requests on a loopback port, replying with a TXT record for CHAOS queries with
+    /// the given content type, and returns its URL
+    fn serve(text: &'static str, content_type: &'static str) -> String {
+        let address = serve_http(move |request| {
+            let query = Message::from_vec(&request.body).expect("valid query");
+            let mut reply = Message::response(query.metadata.id, query.metadata.op_code);
+            for question in &query.queries {
+                reply.add_query(question.clone());
+                if question.query_class() == DNSClass::CH {
+                    let mut record = Record::from_rdata(
+                        question.name().clone(),
+                        0,
+                        RData::TXT(TXT::new(vec![text.to_string()])),
+                    );
+                    record.dns_class = DNSClass::CH;
+                    reply.add_answer(record);
+                }
+            }
+            let headers = format!("Content-Type: {}\r\n", content_type);
+            response("200 OK", &headers, &reply.to_vec().expect("encode"))
+        });
+        format!("http://{}/dns-query", address)
+    }
+
+    #[test]
+    fn test_chaos_txt() {
+        let source = DoHSource::new(
+            serve("1.2.3.4", DNS_MESSAGE),
+            QueryType::ChaosTXT,
+            "whoami.cloudflare",
+        );
+        let ip = block_on(source.get_ip(Family::Any)).expect("valid reply");
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+    }
+
+    #[test]
+    fn test_empty_reply() {
+        let source = DoHSource::new(
+            serve("1.2.3.4", DNS_MESSAGE),
+            QueryType::TXT,
+            "whoami.cloudflare",
+        );
+        assert!(matches!(
+            block_on(source.get_ip(Family::Any)),
+            Err(Error::DnsResolutionEmpty)
+        ));
+    }
+
+    #[test]
+    fn test_content_type() {
+        let url = serve("1.2.3.4", "text/html");
+        let source = DoHSource::new(url, QueryType::ChaosTXT, "whoami.cloudflare");
+        assert!(matches!(
+            block_on(source.get_ip(Family::Any)),
+            Err(Error::HttpContentType { .. })
+        ));
+    }
+}
@@ -0,0 +1,129 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::time::Duration;
+
+use log::trace;
+use tokio::net::UdpSocket;
+
+/// Returns a UDP socket connected to the given gateway
+pub(crate) async fn connect(gateway: SocketAddr) -> io::Result<UdpSocket> {
+    let local = match gateway {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(gateway).await?;
+    Ok(socket)
+}
+
+/// Sends the request until a reply is received, doubling the retransmission interval every time,
+/// for up to `timeout`. Replies longer than `max_len` are truncated.
+pub(crate) async fn exchange(
+    socket: &UdpSocket,
+    request: &[u8],
+    max_len: usize,
+    initial_interval: Duration,
+    timeout: Duration,
+) -> io::Result<Vec<u8>> {
+    let exchange = async {
+        let mut interval = initial_interval;
+        let mut buf = vec![0; max_len];
+        loop {
+            socket.send(request).await?;
+            match tokio::time::timeout(interval, socket.recv(&mut buf)).await {
+                Ok(len) => return Ok(buf[..len?].to_vec()),
+                Err(_) => {
+                    trace!("Gateway request timed out after {:?}", interval);
+                    interval *= 2;
+                }
+            }
+        }
+    };
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+/// Returns the default gateway of the family from the routing table
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub(crate) fn default_gateway(ipv6: bool) -> Option<IpAddr> {
+    if ipv6 {
+        default_gateway_v6()
+    } else {
+        default_gateway_v4()
+    }
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+pub(crate) fn default_gateway(_ipv6: bool) -> Option<IpAddr> {
+    None
+}
+
+/// Returns the default gateway on the given port, with the scope of the interface when it has a
+/// link-local address
+pub(crate) fn default_gateway_address(ipv6: bool, port: u16) -> Option<SocketAddr> {
+    match default_gateway(ipv6)? {
+        IpAddr::V6(ip) if ip.is_unicast_link_local() => Some(SocketAddr::V6(SocketAddrV6::new(
+            ip,
+            port,
+            0,
+            default_gateway_scope()?,
+        ))),
+        ip => Some(SocketAddr::new(ip, port)),
+    }
+}
+
+/// Route flag of routes through a gateway
+#[cfg(any(target_os = "android", target_os = "linux"))]
+const RTF_GATEWAY: u32 = 0x2;
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn default_gateway_v4() -> Option<IpAddr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let destination = u32::from_str_radix(fields.get(1)?, 16).ok()?;
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        let flags = u32::from_str_radix(fields.get(3)?, 16).ok()?;
+        if destination != 0 || flags & RTF_GATEWAY == 0 {
+            return None;
+        }
+        // The addresses are in host byte order
+        Some(IpAddr::V4(Ipv4Addr::from(gateway.to_ne_bytes())))
+    })
+}
+
+/// Returns the IPv6 default route as (gateway, interface)
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn default_route_v6() -> Option<(Ipv6Addr, String)> {
+    let routes = std::fs::read_to_string("/proc/net/ipv6_route").ok()?;
+    routes.lines().find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let destination = u128::from_str_radix(fields.first()?, 16).ok()?;
+        let prefix_len = u8::from_str_radix(fields.get(1)?, 16).ok()?;
+        let gateway = u128::from_str_radix(fields.get(4)?, 16).ok()?;
+        let flags = u32::from_str_radix(fields.get(8)?, 16).ok()?;
+        if destination != 0 || prefix_len != 0 || flags & RTF_GATEWAY == 0 {
+            return None;
+        }
+        Some((Ipv6Addr::from(gateway), fields.get(9)?.to_string()))
+    })
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn default_gateway_v6() -> Option<IpAddr> {
+    default_route_v6().map(|(gateway, _)| IpAddr::V6(gateway))
+}
+
+/// Returns the index of the interface of the IPv6 default route
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn default_gateway_scope() -> Option<u32> {
+    let (_, interface) = default_route_v6()?;
+    let index = std::fs::read_to_string(format!("/sys/class/net/{}/ifindex", interface)).ok()?;
+    index.trim().parse().ok()
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn default_gateway_scope() -> Option<u32> {
+    None
+}
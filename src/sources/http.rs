@@ -1,19 +1,44 @@
 use crate::sources::interfaces::{Error, Family, IpFuture, IpResult, Source};
 use log::trace;
+use rustls_pki_types::CertificateDer;
+use sha2::{Digest, Sha256};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
+
+/// Default limit on the size of the body returned by an HTTP source
+pub const DEFAULT_MAX_BODY_SIZE: usize = 1024;
 
 pub struct HTTPSourceBuilder {
     url: String,
+    ipv4_url: Option<String>,
+    ipv6_url: Option<String>,
     timeout: Duration,
     family: Family,
+    content_types: Vec<String>,
+    max_body_size: usize,
+    root_certificates: Vec<Vec<u8>>,
+    system_roots: bool,
+    spki_pins: Vec<[u8; 32]>,
+    local_address: Option<IpAddr>,
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    interface: Option<String>,
 }
 impl HTTPSourceBuilder {
     pub fn new<S: Into<String>>(url: S) -> Self {
         Self {
             url: url.into(),
+            ipv4_url: None,
+            ipv6_url: None,
             timeout: Duration::from_secs(30),
             family: Family::Any,
+            content_types: Vec::new(),
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            root_certificates: Vec::new(),
+            system_roots: true,
+            spki_pins: Vec::new(),
+            local_address: None,
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            interface: None,
         }
     }
     pub fn with_timeout(mut self, timeout: Duration) -> Self {
@@ -24,16 +49,99 @@ impl HTTPSourceBuilder {
         self.family = family;
         self
     }
+    /// Sets the URL to contact when looking up an IPv4 address (e.g. `https://api4.ipify.org`).
+    ///
+    /// The source will support IPv4 lookups regardless of the supported family.
+    pub fn with_ipv4_url<S: Into<String>>(mut self, url: S) -> Self {
+        self.ipv4_url = Some(url.into());
+        self
+    }
+    /// Sets the URL to contact when looking up an IPv6 address (e.g. `https://api6.ipify.org`).
+    ///
+    /// The source will support IPv6 lookups regardless of the supported family.
+    pub fn with_ipv6_url<S: Into<String>>(mut self, url: S) -> Self {
+        self.ipv6_url = Some(url.into());
+        self
+    }
+    /// Restricts the accepted replies to the given content types (e.g. `text/plain`).
+    ///
+    /// Parameters such as `charset` are ignored when comparing. If no content type is added any
+    /// reply is accepted.
+    pub fn with_content_type<S: Into<String>>(mut self, content_type: S) -> Self {
+        self.content_types
+            .push(content_type.into().trim().to_ascii_lowercase());
+        self
+    }
+    /// Sets the maximum size in bytes of the body of the reply
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+    /// Trusts the root certificates in the PEM bundle in addition to the configured ones.
+    ///
+    /// The bundle is parsed when the source is queried, an invalid bundle makes every query fail.
+    pub fn with_root_certificates_pem<P: Into<Vec<u8>>>(mut self, pem: P) -> Self {
+        self.root_certificates.push(pem.into());
+        self
+    }
+    /// Enables or disables the system trust store (enabled by default).
+    ///
+    /// When disabled only the roots added with `with_root_certificates_pem` are trusted.
+    pub fn with_system_roots(mut self, system_roots: bool) -> Self {
+        self.system_roots = system_roots;
+        self
+    }
+    /// Pins the SHA-256 digest of the DER encoded SubjectPublicKeyInfo of the server certificate.
+    ///
+    /// When at least one pin is set replies are accepted only over TLS and only if the leaf
+    /// certificate of the server matches one of the pins.
+    pub fn with_spki_pin(mut self, sha256: [u8; 32]) -> Self {
+        self.spki_pins.push(sha256);
+        self
+    }
+    /// Sends the requests from the given local address.
+    ///
+    /// The source will only support the family of the address.
+    pub fn with_local_address(mut self, local_address: IpAddr) -> Self {
+        self.local_address = Some(local_address);
+        self
+    }
+    /// Binds the connections to the given network interface (`SO_BINDTODEVICE`)
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    pub fn with_interface<S: Into<String>>(mut self, interface: S) -> Self {
+        self.interface = Some(interface.into());
+        self
+    }
     pub fn build(self) -> HTTPSource {
         let Self {
             url,
+            ipv4_url,
+            ipv6_url,
             timeout,
             family,
+            content_types,
+            max_body_size,
+            root_certificates,
+            system_roots,
+            spki_pins,
+            local_address,
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            interface,
         } = self;
         HTTPSource {
             url,
+            ipv4_url,
+            ipv6_url,
             timeout,
             family,
+            content_types,
+            max_body_size,
+            root_certificates,
+            system_roots,
+            spki_pins,
+            local_address,
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            interface,
         }
     }
 }
@@ -42,35 +150,178 @@ impl HTTPSourceBuilder {
 ///
 /// It expects a URL to contact to retrive in the content of the message the IP
 /// without any additional processing (if not trimming the string).
+///
+/// Replies without a success status, with a content type not in the configured allowlist or with
+/// a body larger than the configured limit are rejected.
 #[derive(Debug, Clone)]
 pub struct HTTPSource {
     url: String,
+    ipv4_url: Option<String>,
+    ipv6_url: Option<String>,
     timeout: Duration,
     family: Family,
+    content_types: Vec<String>,
+    max_body_size: usize,
+    root_certificates: Vec<Vec<u8>>,
+    system_roots: bool,
+    spki_pins: Vec<[u8; 32]>,
+    local_address: Option<IpAddr>,
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    interface: Option<String>,
+}
+
+impl HTTPSource {
+    /// Returns the URL to contact for the family, if the family is supported
+    fn url(&self, family: Family) -> Option<&str> {
+        let family_url = match family {
+            Family::IPv4 => self.ipv4_url.as_deref(),
+            Family::IPv6 => self.ipv6_url.as_deref(),
+            Family::Any => None,
+        };
+        if family_url.is_some() {
+            family_url
+        } else if self.family == Family::Any || family == Family::Any || self.family == family {
+            Some(&self.url)
+        } else {
+            None
+        }
+    }
+
+    fn client(&self, family: Family) -> Result<reqwest::Client, Error> {
+        let mut client = client_builder(self.timeout, self.local_address, family)?;
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(interface) = &self.interface {
+            client = client.interface(interface);
+        }
+
+        let mut certificates = Vec::new();
+        for pem in &self.root_certificates {
+            certificates.extend(reqwest::Certificate::from_pem_bundle(pem)?);
+        }
+        client = if self.system_roots {
+            client.tls_certs_merge(certificates)
+        } else {
+            client.tls_certs_only(certificates)
+        };
+        if !self.spki_pins.is_empty() {
+            client = client.tls_info(true);
+        }
+        Ok(client.build()?)
+    }
+
+    fn check_pins(&self, resp: &reqwest::Response) -> Result<(), Error> {
+        if self.spki_pins.is_empty() {
+            return Ok(());
+        }
+        let digest = resp
+            .extensions()
+            .get::<reqwest::tls::TlsInfo>()
+            .and_then(|info| info.peer_certificate())
+            .and_then(spki_sha256);
+        match digest {
+            Some(digest) if self.spki_pins.contains(&digest) => Ok(()),
+            _ => Err(Error::TlsPinMismatch),
+        }
+    }
+}
+
+/// Returns a client builder with the timeout, bound to the local address or to the unspecified
+/// address of the family
+pub(crate) fn client_builder(
+    timeout: Duration,
+    local_address: Option<IpAddr>,
+    family: Family,
+) -> Result<reqwest::ClientBuilder, Error> {
+    let client = reqwest::Client::builder().timeout(timeout);
+    Ok(match (local_address, family) {
+        (Some(IpAddr::V4(address)), Family::IPv4 | Family::Any) => {
+            client.local_address(IpAddr::V4(address))
+        }
+        (Some(IpAddr::V6(address)), Family::IPv6 | Family::Any) => {
+            client.local_address(IpAddr::V6(address))
+        }
+        (Some(_), _) => return Err(Error::UnsupportedFamily),
+        (None, Family::IPv4) => client.local_address(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))),
+        (None, Family::IPv6) => {
+            client.local_address(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)))
+        }
+        (None, Family::Any) => client,
+    })
+}
+
+/// Checks the media type of the response against the accepted ones, any is accepted if empty
+pub(crate) fn check_content_type(
+    resp: &reqwest::Response,
+    content_types: &[String],
+) -> Result<(), Error> {
+    if content_types.is_empty() {
+        return Ok(());
+    }
+    let content_type = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let essence = content_type
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase());
+    match essence {
+        Some(essence) if content_types.contains(&essence) => Ok(()),
+        _ => Err(Error::HttpContentType {
+            status: resp.status(),
+            content_type: content_type.map(String::from),
+        }),
+    }
+}
+
+/// Reads the body of the response, failing once it grows larger than `max_body_size`
+pub(crate) async fn read_body(
+    mut resp: reqwest::Response,
+    max_body_size: usize,
+) -> Result<Vec<u8>, Error> {
+    let status = resp.status();
+    let too_large = Error::HttpBodyTooLarge {
+        status,
+        limit: max_body_size,
+    };
+    if resp
+        .content_length()
+        .is_some_and(|length| length > max_body_size as u64)
+    {
+        return Err(too_large);
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        if body.len() + chunk.len() > max_body_size {
+            return Err(too_large);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
 }
 
 impl Source for HTTPSource {
     fn get_ip(&self, family: Family) -> IpFuture<'_> {
         async fn run(_self: &HTTPSource, family: Family) -> IpResult {
-            if !((_self.family == Family::Any)
-                || (family == Family::Any)
-                || (_self.family == family))
-            {
+            let Some(url) = _self.url(family) else {
                 return Err(Error::UnsupportedFamily);
-            }
+            };
 
-            trace!("Contacting {:?}", _self.url);
-            let client = reqwest::Client::builder().timeout(_self.timeout);
-            let client = match family {
-                Family::IPv4 => client.local_address(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))),
-                Family::IPv6 => {
-                    client.local_address(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)))
-                }
-                Family::Any => client,
+            trace!("Contacting {:?}", url);
+            let client = _self.client(family)?;
+            let resp = client.get(url).send().await?;
+            _self.check_pins(&resp)?;
+            if !resp.status().is_success() {
+                return Err(match retry_after(&resp) {
+                    Some(retry_after) => Error::RateLimited {
+                        status: resp.status(),
+                        retry_after,
+                    },
+                    None => Error::HttpStatus(resp.status()),
+                });
             }
-            .build()?;
-            let resp = client.get(&_self.url).send().await?.text().await?;
-            let parsed_ip: IpAddr = resp.trim().parse()?;
+            check_content_type(&resp, &_self.content_types)?;
+            let body = read_body(resp, _self.max_body_size).await?;
+            let parsed_ip: IpAddr = std::str::from_utf8(&body)?.trim().parse()?;
             match (family, parsed_ip) {
                 (Family::Any, _)
                 | (Family::IPv4, IpAddr::V4(_))
@@ -89,36 +340,393 @@ impl Source for HTTPSource {
 
 impl std::fmt::Display for HTTPSource {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "HttpSource: {}", self.url)
+        write!(f, "HttpSource: {}", self.url)?;
+        let family_urls: Vec<String> = [("IPv4", &self.ipv4_url), ("IPv6", &self.ipv6_url)]
+            .into_iter()
+            .filter_map(|(family, url)| Some(format!("{} {}", family, url.as_ref()?)))
+            .collect();
+        if !family_urls.is_empty() {
+            write!(f, " ({})", family_urls.join(", "))?;
+        }
+        Ok(())
     }
 }
 
+/// Returns when the server asked to be contacted again in a rate limiting reply (429 or 503)
+fn retry_after(resp: &reqwest::Response) -> Option<SystemTime> {
+    if !matches!(
+        resp.status(),
+        reqwest::StatusCode::TOO_MANY_REQUESTS | reqwest::StatusCode::SERVICE_UNAVAILABLE
+    ) {
+        return None;
+    }
+    let value = resp
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => SystemTime::now().checked_add(Duration::from_secs(seconds)),
+        Err(_) => httpdate::parse_http_date(value).ok(),
+    }
+}
+
+/// Returns the SHA-256 digest of the SubjectPublicKeyInfo of a DER encoded X.509 certificate
+fn spki_sha256(certificate: &[u8]) -> Option<[u8; 32]> {
+    let certificate = CertificateDer::from(certificate);
+    let certificate = webpki::EndEntityCert::try_from(&certificate).ok()?;
+    Some(Sha256::digest(certificate.subject_public_key_info()).into())
+}
+
 /// Returns a collection of HTTP(s) sources to use to retrieve the external ip
 pub fn get_http_sources<T>() -> T
 where
     T: std::iter::FromIterator<Box<dyn Source>>,
 {
+    // Family supported by the URL, and the dedicated endpoint for each family if the service has
+    // one
     [
-        ("https://icanhazip.com/", Family::Any),
-        ("https://myexternalip.com/raw", Family::Any),
-        ("https://ifconfig.io/ip", Family::Any),
-        ("https://ipecho.net/plain", Family::Any),
-        ("https://checkip.amazonaws.com/", Family::IPv4),
-        ("https://ident.me/", Family::Any),
-        ("http://whatismyip.akamai.com/", Family::IPv4),
-        ("https://myip.dnsomatic.com/", Family::IPv4),
-        ("https://api.ipify.org", Family::IPv4),
-        ("https://ifconfig.me/ip", Family::Any),
-        ("https://ipinfo.io/ip", Family::IPv4),
-        ("https://ip2location.io/ip", Family::Any),
+        (
+            "https://icanhazip.com/",
+            Family::Any,
+            Some("https://ipv4.icanhazip.com/"),
+            Some("https://ipv6.icanhazip.com/"),
+        ),
+        ("https://myexternalip.com/raw", Family::Any, None, None),
+        ("https://ifconfig.io/ip", Family::Any, None, None),
+        ("https://ipecho.net/plain", Family::Any, None, None),
+        ("https://checkip.amazonaws.com/", Family::IPv4, None, None),
+        (
+            "https://ident.me/",
+            Family::Any,
+            Some("https://v4.ident.me/"),
+            Some("https://v6.ident.me/"),
+        ),
+        (
+            "http://whatismyip.akamai.com/",
+            Family::IPv4,
+            Some("http://ipv4.whatismyip.akamai.com/"),
+            Some("http://ipv6.whatismyip.akamai.com/"),
+        ),
+        ("https://myip.dnsomatic.com/", Family::IPv4, None, None),
+        (
+            "https://api.ipify.org",
+            Family::IPv4,
+            Some("https://api4.ipify.org"),
+            Some("https://api6.ipify.org"),
+        ),
+        ("https://ifconfig.me/ip", Family::Any, None, None),
+        (
+            "https://ipinfo.io/ip",
+            Family::IPv4,
+            None,
+            Some("https://v6.ipinfo.io/ip"),
+        ),
+        ("https://ip2location.io/ip", Family::Any, None, None),
     ]
     .iter()
     .cloned()
-    .map(|(url, family)| {
-        HTTPSourceBuilder::new(url)
-            .with_supported_family(family)
-            .build()
+    .map(|(url, family, ipv4_url, ipv6_url)| {
+        let mut builder = HTTPSourceBuilder::new(url).with_supported_family(family);
+        if let Some(ipv4_url) = ipv4_url {
+            builder = builder.with_ipv4_url(ipv4_url);
+        }
+        if let Some(ipv6_url) = ipv6_url {
+            builder = builder.with_ipv6_url(ipv6_url);
+        }
+        builder.build()
     })
     .map(|x| -> Box<dyn Source> { Box::new(x) })
     .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::sources::test_util::{read_request, response, serve_http};
+    use std::io::Write;
+    use std::net::{Ipv4Addr, TcpListener};
+    use tokio_test::block_on;
+
+    /// Serves a canned HTTP response on a loopback port and returns its URL
+    fn serve(response: Vec<u8>) -> String {
+        format!("http://{}/", serve_http(move |_| response.clone()))
+    }
+
+    /// Serves a single canned HTTPS response on a loopback port with the self-signed certificate
+    /// of `tests/data/reflector.pem`, and returns its URL
+    fn serve_tls(response: Vec<u8>) -> String {
+        use rustls::pki_types::PrivateKeyDer;
+        use std::sync::Arc;
+
+        let certificate = include_bytes!("../../tests/data/reflector.der");
+        let key = include_bytes!("../../tests/data/reflector.key.der");
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .expect("protocol versions")
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from(&certificate[..]).into_owned()],
+                PrivateKeyDer::try_from(&key[..]).expect("key").clone_key(),
+            )
+            .expect("certificate");
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).expect("bind");
+        let addr = listener.local_addr().expect("local address");
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("accept");
+            let connection = rustls::ServerConnection::new(Arc::new(config)).expect("connection");
+            let mut stream = rustls::StreamOwned::new(connection, stream);
+            if read_request(&mut stream).is_some() {
+                let _ = stream.write_all(&response);
+                stream.conn.send_close_notify();
+                let _ = stream.flush();
+            }
+        });
+        format!("https://{}/", addr)
+    }
+
+    fn reply_with_headers(status: &str, headers: &str, body: &str) -> Vec<u8> {
+        response(status, headers, body.as_bytes())
+    }
+
+    fn reply(status: &str, content_type: &str, body: &str) -> Vec<u8> {
+        let headers = format!("Content-Type: {}\r\n", content_type);
+        response(status, &headers, body.as_bytes())
+    }
+
+    #[test]
+    fn test_success() {
+        let url = serve(reply("200 OK", "text/plain", "1.2.3.4\n"));
+        let source = HTTPSourceBuilder::new(url)
+            .with_content_type("text/plain")
+            .build();
+        let ip = block_on(source.get_ip(Family::Any)).expect("valid reply");
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+    }
+
+    #[test]
+    fn test_error_status() {
+        let url = serve(reply("503 Service Unavailable", "text/html", "busy"));
+        let source = HTTPSourceBuilder::new(url).build();
+        let result = block_on(source.get_ip(Family::Any));
+        assert!(matches!(
+            result,
+            Err(Error::HttpStatus(status)) if status == reqwest::StatusCode::SERVICE_UNAVAILABLE
+        ));
+    }
+
+    #[test]
+    fn test_content_type_not_accepted() {
+        let url = serve(reply("200 OK", "text/html; charset=utf-8", "1.2.3.4"));
+        let source = HTTPSourceBuilder::new(url)
+            .with_content_type("text/plain")
+            .build();
+        let result = block_on(source.get_ip(Family::Any));
+        assert!(matches!(
+            result,
+            Err(Error::HttpContentType { content_type: Some(ref content_type), .. })
+                if content_type == "text/html; charset=utf-8"
+        ));
+    }
+
+    #[test]
+    fn test_body_too_large() {
+        let url = serve(reply("200 OK", "text/plain", &"1".repeat(64)));
+        let source = HTTPSourceBuilder::new(url).with_max_body_size(16).build();
+        let result = block_on(source.get_ip(Family::Any));
+        assert!(matches!(
+            result,
+            Err(Error::HttpBodyTooLarge { limit: 16, .. })
+        ));
+    }
+
+    #[test]
+    fn test_body_too_large_without_length() {
+        let url = serve(
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\n{}",
+                "1".repeat(64)
+            )
+            .into_bytes(),
+        );
+        let source = HTTPSourceBuilder::new(url).with_max_body_size(16).build();
+        let result = block_on(source.get_ip(Family::Any));
+        assert!(matches!(
+            result,
+            Err(Error::HttpBodyTooLarge { limit: 16, .. })
+        ));
+    }
+
+    #[test]
+    fn test_spki_sha256() {
+        let certificate = include_bytes!("../../tests/data/reflector.der");
+        let expected = [
+            0x9a, 0xc5, 0x17, 0x67, 0x4a, 0x85, 0x4c, 0x90, 0x19, 0x87, 0x8c, 0x02, 0x8a, 0x8b,
+            0x6a, 0xd5, 0xa6, 0xa3, 0xb5, 0x3c, 0xf6, 0x42, 0xd8, 0x9a, 0xf8, 0xaf, 0x26, 0x02,
+            0x3d, 0x1f, 0x57, 0x8e,
+        ];
+        assert_eq!(spki_sha256(certificate), Some(expected));
+        assert_eq!(spki_sha256(&certificate[..100]), None);
+    }
+
+    #[test]
+    fn test_spki_pin_over_tls() {
+        let pem = include_bytes!("../../tests/data/reflector.pem");
+        let certificate = include_bytes!("../../tests/data/reflector.der");
+        let pin = spki_sha256(certificate).expect("valid certificate");
+        for (pin, pinned) in [(pin, true), ([0; 32], false)] {
+            let url = serve_tls(reply("200 OK", "text/plain", "1.2.3.4"));
+            let source = HTTPSourceBuilder::new(url)
+                .with_root_certificates_pem(&pem[..])
+                .with_system_roots(false)
+                .with_spki_pin(pin)
+                .build();
+            let result = block_on(source.get_ip(Family::Any));
+            if pinned {
+                assert!(result.is_ok(), "{result:?}");
+            } else {
+                assert!(matches!(result, Err(Error::TlsPinMismatch)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_pinned_without_tls() {
+        let url = serve(reply("200 OK", "text/plain", "1.2.3.4"));
+        let source = HTTPSourceBuilder::new(url).with_spki_pin([0; 32]).build();
+        let result = block_on(source.get_ip(Family::Any));
+        assert!(matches!(result, Err(Error::TlsPinMismatch)));
+    }
+
+    #[test]
+    fn test_root_certificates_pem() {
+        let pem = include_bytes!("../../tests/data/reflector.pem");
+        let url = serve_tls(reply("200 OK", "text/plain", "1.2.3.4"));
+        let source = HTTPSourceBuilder::new(url)
+            .with_root_certificates_pem(&pem[..])
+            .with_system_roots(false)
+            .build();
+        let ip = block_on(source.get_ip(Family::Any)).expect("trusted certificate");
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+
+        // The self-signed certificate is rejected without the custom root
+        let url = serve_tls(reply("200 OK", "text/plain", "1.2.3.4"));
+        let source = HTTPSourceBuilder::new(url).build();
+        assert!(matches!(
+            block_on(source.get_ip(Family::Any)),
+            Err(Error::Http(_))
+        ));
+
+        let source = HTTPSourceBuilder::new("http://127.0.0.1/")
+            .with_root_certificates_pem("-----BEGIN CERTIFICATE-----\ninvalid\n")
+            .build();
+        assert!(matches!(
+            block_on(source.get_ip(Family::Any)),
+            Err(Error::Http(_))
+        ));
+    }
+
+    #[test]
+    fn test_local_address() {
+        let url = serve(reply("200 OK", "text/plain", "1.2.3.4"));
+        let source = HTTPSourceBuilder::new(url)
+            .with_local_address(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .build();
+        assert!(block_on(source.get_ip(Family::Any)).is_ok());
+        assert!(matches!(
+            block_on(source.get_ip(Family::IPv6)),
+            Err(Error::UnsupportedFamily)
+        ));
+    }
+
+    #[test]
+    fn test_rate_limited() {
+        let url = serve(reply_with_headers(
+            "429 Too Many Requests",
+            "Retry-After: 120\r\n",
+            "slow down",
+        ));
+        let source = HTTPSourceBuilder::new(url).build();
+        let result = block_on(source.get_ip(Family::Any));
+        let Err(Error::RateLimited {
+            status,
+            retry_after,
+        }) = result
+        else {
+            panic!("Unexpected result {:?}", result);
+        };
+        assert_eq!(status, reqwest::StatusCode::TOO_MANY_REQUESTS);
+        let delay = retry_after
+            .duration_since(SystemTime::now())
+            .expect("retry in the future");
+        assert!(delay > Duration::from_secs(100) && delay <= Duration::from_secs(120));
+    }
+
+    #[test]
+    fn test_rate_limited_http_date() {
+        let url = serve(reply_with_headers(
+            "503 Service Unavailable",
+            "Retry-After: Wed, 21 Oct 2065 07:28:00 GMT\r\n",
+            "busy",
+        ));
+        let source = HTTPSourceBuilder::new(url).build();
+        let result = block_on(source.get_ip(Family::Any));
+        assert!(matches!(
+            result,
+            Err(Error::RateLimited { retry_after, .. })
+                if retry_after == httpdate::parse_http_date("Wed, 21 Oct 2065 07:28:00 GMT").unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_per_family_url() {
+        let url = serve(reply("200 OK", "text/plain", "1.2.3.4"));
+        let source = HTTPSourceBuilder::new("http://127.0.0.1:1/")
+            .with_supported_family(Family::IPv6)
+            .with_ipv4_url(url)
+            .build();
+        let ip = block_on(source.get_ip(Family::IPv4)).expect("valid reply");
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+
+        let source = HTTPSourceBuilder::new("http://127.0.0.1:1/")
+            .with_supported_family(Family::IPv4)
+            .build();
+        assert!(matches!(
+            block_on(source.get_ip(Family::IPv6)),
+            Err(Error::UnsupportedFamily)
+        ));
+    }
+
+    #[test]
+    fn test_display_family_urls() {
+        let source = HTTPSourceBuilder::new("https://ident.me/")
+            .with_ipv4_url("https://v4.ident.me/")
+            .with_ipv6_url("https://v6.ident.me/")
+            .build();
+        assert_eq!(
+            source.to_string(),
+            "HttpSource: https://ident.me/ (IPv4 https://v4.ident.me/, IPv6 https://v6.ident.me/)"
+        );
+        let source = HTTPSourceBuilder::new("https://ifconfig.me/ip").build();
+        assert_eq!(source.to_string(), "HttpSource: https://ifconfig.me/ip");
+    }
+
+    #[test]
+    fn test_http_sources_order() {
+        let sources: Vec<Box<dyn Source>> = get_http_sources();
+        assert_eq!(sources.len(), 12);
+        assert!(
+            sources[0]
+                .to_string()
+                .starts_with("HttpSource: https://icanhazip.com/ ")
+        );
+        assert!(
+            sources[11]
+                .to_string()
+                .starts_with("HttpSource: https://ip2location.io/ip")
+        );
+    }
+}
@@ -1,50 +1,326 @@
-/* use crate::sources::interfaces; */
 use crate::sources::interfaces::{Error, Family, IpFuture, IpResult, Source};
-use std::net::IpAddr;
-use std::pin::Pin;
-use std::sync::mpsc;
+use std::net::{IpAddr, SocketAddr, SocketAddrV4};
 use std::sync::{Arc, Mutex};
-use std::task::{Poll, Waker};
-use std::thread;
+use std::time::{Duration, Instant};
 
-use log::trace;
+use igd::PortMappingProtocol;
+use igd::aio::Gateway;
+use log::{error, trace};
+use tokio::task::JoinHandle;
+
+pub struct IGDBuilder {
+    timeout: Option<Duration>,
+    bind_address: Option<SocketAddr>,
+    broadcast_address: Option<SocketAddr>,
+}
+
+impl IGDBuilder {
+    pub fn new() -> Self {
+        Self {
+            timeout: None,
+            bind_address: None,
+            broadcast_address: None,
+        }
+    }
+    /// Sets how long to wait for the SSDP reply of the gateway (10 seconds by default)
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+    /// Sends the search from the given local address, to pick the LAN interface to search on
+    pub fn with_bind_address(mut self, bind_address: SocketAddr) -> Self {
+        self.bind_address = Some(bind_address);
+        self
+    }
+    /// Sends the search to the given address instead of `239.255.255.250:1900`
+    pub fn with_broadcast_address(mut self, broadcast_address: SocketAddr) -> Self {
+        self.broadcast_address = Some(broadcast_address);
+        self
+    }
+    pub fn build(self) -> IGD {
+        let Self {
+            timeout,
+            bind_address,
+            broadcast_address,
+        } = self;
+        IGD {
+            timeout,
+            bind_address,
+            broadcast_address,
+            gateway: Arc::default(),
+        }
+    }
+}
+
+impl Default for IGDBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 /// IGD Source of the external ip
 ///
 /// It will try to connect to the local router implementing the IGD interface to obtain the external
 /// IP directly from it.
 ///
+/// The lookup runs on the async API of `igd`, which needs a Tokio runtime. Dropping the future
+/// cancels the search.
+///
+/// The discovered gateway is reused, also by clones of the source, until a request to it fails.
+///
 /// The feature "igd" must be enabled to use this t(on by default)
 #[derive(Debug, Clone)]
-pub struct IGD {}
+pub struct IGD {
+    timeout: Option<Duration>,
+    bind_address: Option<SocketAddr>,
+    broadcast_address: Option<SocketAddr>,
+    gateway: Arc<Mutex<Option<Gateway>>>,
+}
 
 impl IGD {
     pub fn source() -> Box<dyn Source> {
-        Box::new(IGD {})
+        Box::new(IGDBuilder::new().build())
+    }
+
+    fn search_options(&self) -> igd::SearchOptions {
+        let mut options = igd::SearchOptions::default();
+        if let Some(timeout) = self.timeout {
+            options.timeout = Some(timeout);
+        }
+        if let Some(bind_address) = self.bind_address {
+            options.bind_addr = bind_address;
+        }
+        if let Some(broadcast_address) = self.broadcast_address {
+            options.broadcast_address = broadcast_address;
+        }
+        options
+    }
+
+    /// Returns the cached gateway, searching it if needed, and whether it was cached
+    async fn search(&self) -> Result<(Gateway, bool), Error> {
+        if let Some(gateway) = self.gateway.lock().unwrap().clone() {
+            trace!("Reusing IGD gateway at {}", gateway.addr);
+            return Ok((gateway, true));
+        }
+        trace!("Searching IGD gateway");
+        let gateway = igd::aio::search_gateway(self.search_options()).await?;
+        trace!("IGD gateway found at {}", gateway.addr);
+        *self.gateway.lock().unwrap() = Some(gateway.clone());
+        Ok((gateway, false))
+    }
+
+    /// Runs the request on the gateway, searching it again if the cached one can't be reached
+    ///
+    /// Errors returned by the gateway itself (e.g. a port already in use) are not retried.
+    async fn with_gateway<F, Fut, T>(&self, request: F) -> Result<T, Error>
+    where
+        F: Fn(Gateway) -> Fut,
+        Fut: std::future::Future<Output = Result<T, Error>>,
+    {
+        let (gateway, cached) = self.search().await?;
+        match request(gateway).await {
+            Err(err) if is_transport_error(&err) => {
+                self.gateway.lock().unwrap().take();
+                if !cached {
+                    return Err(err);
+                }
+                trace!("Cached IGD gateway failed, searching again: {}", err);
+                request(self.search().await?.0).await
+            }
+            result => result,
+        }
+    }
+
+    /// Maps the external port of the gateway to the local address for the lease duration
+    ///
+    /// A free external port is chosen by the gateway if `external_port` is 0, a lease of 0 makes a
+    /// permanent mapping.
+    pub async fn add_port_mapping(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease: Duration,
+        description: &str,
+    ) -> Result<PortMapping, Error> {
+        self.with_gateway(|gateway| {
+            PortMapping::add(
+                gateway,
+                protocol,
+                external_port,
+                local_addr,
+                lease,
+                description,
+            )
+        })
+        .await
     }
 }
 
-impl Source for IGD {
-    fn get_ip(&self, family: Family) -> IpFuture<'_> {
-        let (tx, rx) = mpsc::channel();
-        let future = IGDFuture {
-            rx,
-            waker: Arc::new(Mutex::from(None)),
-            family,
+/// Returns true if the request didn't reach the gateway, rather than being refused by it
+fn is_transport_error(err: &Error) -> bool {
+    let err = match err {
+        Error::IgdExternalIp(igd::GetExternalIpError::RequestError(err))
+        | Error::IgdAddPort(igd::AddPortError::RequestError(err))
+        | Error::IgdAddAnyPort(igd::AddAnyPortError::RequestError(err))
+        | Error::IgdRemovePort(igd::RemovePortError::RequestError(err)) => err,
+        _ => return false,
+    };
+    matches!(
+        err,
+        igd::RequestError::HyperError(_)
+            | igd::RequestError::HttpError(_)
+            | igd::RequestError::IoError(_)
+    )
+}
+
+/// Lease of a port mapping, shared with the renewal task
+#[derive(Debug)]
+struct MappingLease {
+    gateway: Gateway,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+    local_addr: SocketAddrV4,
+    lease: Duration,
+    description: String,
+    expires_at: Mutex<Option<Instant>>,
+}
+
+impl MappingLease {
+    fn lease_seconds(&self) -> u32 {
+        u32::try_from(self.lease.as_secs()).unwrap_or(u32::MAX)
+    }
+
+    fn update_expiration(&self) {
+        let expires_at = (!self.lease.is_zero()).then(|| Instant::now() + self.lease);
+        *self.expires_at.lock().unwrap() = expires_at;
+    }
+
+    async fn renew(&self) -> Result<(), Error> {
+        trace!(
+            "Renewing {} port mapping {} -> {}",
+            self.protocol, self.external_port, self.local_addr
+        );
+        self.gateway
+            .add_port(
+                self.protocol,
+                self.external_port,
+                self.local_addr,
+                self.lease_seconds(),
+                &self.description,
+            )
+            .await?;
+        self.update_expiration();
+        Ok(())
+    }
+}
+
+/// Port mapping opened on the IGD gateway
+///
+/// Dropping the mapping stops the automatic renewal, but leaves the mapping on the gateway until
+/// its lease expires, use [`PortMapping::remove`] to remove it.
+#[derive(Debug)]
+pub struct PortMapping {
+    lease: Arc<MappingLease>,
+    renewal: Option<JoinHandle<()>>,
+}
+
+impl PortMapping {
+    async fn add(
+        gateway: Gateway,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease: Duration,
+        description: &str,
+    ) -> Result<PortMapping, Error> {
+        let mut lease = MappingLease {
+            gateway,
+            protocol,
+            external_port,
+            local_addr,
+            lease,
+            description: description.to_string(),
+            expires_at: Mutex::new(None),
         };
-        future.run(tx);
-        Box::pin(future)
+        if external_port == 0 {
+            lease.external_port = lease
+                .gateway
+                .add_any_port(protocol, local_addr, lease.lease_seconds(), description)
+                .await?;
+            lease.update_expiration();
+        } else {
+            lease.renew().await?;
+        }
+        Ok(PortMapping {
+            lease: Arc::new(lease),
+            renewal: None,
+        })
     }
 
-    fn box_clone(&self) -> Box<dyn Source> {
-        Box::new(self.clone())
+    pub fn protocol(&self) -> PortMappingProtocol {
+        self.lease.protocol
+    }
+
+    pub fn external_port(&self) -> u16 {
+        self.lease.external_port
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.lease.local_addr
+    }
+
+    /// Returns when the lease of the mapping expires, None for permanent mappings
+    pub fn expires_at(&self) -> Option<Instant> {
+        *self.lease.expires_at.lock().unwrap()
+    }
+
+    /// Renews the lease of the mapping
+    pub async fn renew(&self) -> Result<(), Error> {
+        self.lease.renew().await
+    }
+
+    /// Renews the lease in a background task every half of its duration, until the mapping is
+    /// removed or dropped
+    ///
+    /// It must be called from a Tokio runtime.
+    pub fn renew_automatically(&mut self) {
+        if self.lease.lease.is_zero() || self.renewal.is_some() {
+            return;
+        }
+        let lease = self.lease.clone();
+        self.renewal = Some(tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(lease.lease / 2).await;
+                if let Err(err) = lease.renew().await {
+                    error!(
+                        "Failed to renew port mapping {} -> {}: {}",
+                        lease.external_port, lease.local_addr, err
+                    );
+                }
+            }
+        }));
+    }
+
+    /// Removes the mapping from the gateway
+    pub async fn remove(mut self) -> Result<(), Error> {
+        if let Some(renewal) = self.renewal.take() {
+            renewal.abort();
+        }
+        self.lease
+            .gateway
+            .remove_port(self.lease.protocol, self.lease.external_port)
+            .await?;
+        Ok(())
     }
 }
 
-struct IGDFuture {
-    rx: mpsc::Receiver<IpResult>,
-    waker: Arc<Mutex<Option<Waker>>>,
-    family: Family,
+impl Drop for PortMapping {
+    fn drop(&mut self) {
+        if let Some(renewal) = self.renewal.take() {
+            renewal.abort();
+        }
+    }
 }
 
 impl std::fmt::Display for IGD {
@@ -53,47 +329,274 @@ impl std::fmt::Display for IGD {
     }
 }
 
-impl IGDFuture {
-    pub fn run(&self, tx: mpsc::Sender<IpResult>) {
-        let waker = self.waker.clone();
-        if matches!(self.family, Family::IPv4 | Family::Any) {
-            thread::spawn(move || {
-                trace!("IGD Future thread started");
-                fn inner() -> IpResult {
-                    let gateway = igd::search_gateway(Default::default())?;
-                    let ip = gateway.get_external_ip()?;
+impl Source for IGD {
+    fn get_ip(&self, family: Family) -> IpFuture<'_> {
+        async fn run(_self: &IGD, family: Family) -> IpResult {
+            if !matches!(family, Family::IPv4 | Family::Any) {
+                return Err(Error::UnsupportedFamily);
+            }
+            _self
+                .with_gateway(|gateway| async move {
+                    let ip = gateway.get_external_ip().await?;
                     Ok(IpAddr::from(ip))
-                }
-
-                let result = inner();
-                log::debug!("IGD task completed: {:?}", result);
-                let r = tx.send(IpResult::from(result));
-                log::debug!("Send result: {:?}", r);
-
-                if let Some(waker) = waker.lock().unwrap().take() {
-                    waker.wake();
-                }
-            });
+                })
+                .await
         }
+        Box::pin(run(self, family))
+    }
+
+    fn box_clone(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
     }
 }
 
-impl std::future::Future for IGDFuture {
-    type Output = IpResult;
-
-    fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context) -> Poll<Self::Output> {
-        if matches!(self.family, Family::IPv4 | Family::Any) {
-            let r = self.rx.try_recv();
-            match r {
-                Err(_) => {
-                    let mut waker = self.waker.lock().unwrap();
-                    *waker = Some(cx.waker().clone());
-                    Poll::Pending
-                }
-                Ok(x) => Poll::Ready(x),
-            }
-        } else {
-            Poll::Ready(std::result::Result::Err(Error::UnsupportedFamily))
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::test_util::{response, serve_http};
+    use std::collections::HashMap;
+    use std::net::{Ipv4Addr, UdpSocket};
+    use std::sync::mpsc;
+    use tokio_test::block_on;
+
+    /// Runs a stand-in IGD control endpoint on a loopback port accepting every action, or
+    /// refusing them with the given UPnP error, and returns a gateway pointing at it and the
+    /// actions it received
+    fn serve(fault: Option<(u16, &'static str)>) -> (Gateway, mpsc::Receiver<String>) {
+        let (tx, rx) = mpsc::channel();
+        let addr = serve_http(move |request| {
+            let body = String::from_utf8_lossy(&request.body);
+            let action = [
+                "GetExternalIPAddress",
+                "AddAnyPortMapping",
+                "AddPortMapping",
+                "DeletePortMapping",
+            ]
+            .into_iter()
+            .find(|action| body.contains(&format!(":{} ", action)))
+            .expect("known action");
+            let reply = match fault {
+                Some((code, description)) => format!(
+                    "<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>\
+                    <s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring>\
+                    <detail><UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\">\
+                    <errorCode>{code}</errorCode><errorDescription>{description}</errorDescription>\
+                    </UPnPError></detail></s:Fault></s:Body></s:Envelope>"
+                ),
+                None => format!(
+                    "<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>\
+                    <u:{action}Response xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">\
+                    <NewReservedPort>4242</NewReservedPort>\
+                    <NewExternalIPAddress>1.2.3.4</NewExternalIPAddress></u:{action}Response></s:Body></s:Envelope>"
+                ),
+            };
+            let status = if fault.is_some() {
+                "500 Internal Server Error"
+            } else {
+                "200 OK"
+            };
+            let _ = tx.send(action.to_string());
+            response(status, "Content-Type: text/xml\r\n", reply.as_bytes())
+        });
+        let addr = match addr {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let arguments = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        let control_schema = HashMap::from([
+            (
+                String::from("AddPortMapping"),
+                arguments(&[
+                    "NewRemoteHost",
+                    "NewExternalPort",
+                    "NewProtocol",
+                    "NewInternalPort",
+                    "NewInternalClient",
+                    "NewEnabled",
+                    "NewPortMappingDescription",
+                    "NewLeaseDuration",
+                ]),
+            ),
+            (
+                String::from("AddAnyPortMapping"),
+                arguments(&[
+                    "NewRemoteHost",
+                    "NewExternalPort",
+                    "NewProtocol",
+                    "NewInternalPort",
+                    "NewInternalClient",
+                    "NewEnabled",
+                    "NewPortMappingDescription",
+                    "NewLeaseDuration",
+                ]),
+            ),
+            (
+                String::from("DeletePortMapping"),
+                arguments(&["NewRemoteHost", "NewExternalPort", "NewProtocol"]),
+            ),
+        ]);
+        let gateway = Gateway {
+            addr,
+            root_url: String::from("/"),
+            control_url: String::from("/control"),
+            control_schema_url: String::from("/scpd.xml"),
+            control_schema,
+        };
+        (gateway, rx)
+    }
+
+    #[test]
+    fn test_unsupported_family() {
+        let source = IGDBuilder::new().build();
+        assert!(matches!(
+            block_on(source.get_ip(Family::IPv6)),
+            Err(Error::UnsupportedFamily)
+        ));
+    }
+
+    #[test]
+    fn test_search_timeout() {
+        // Stand-in gateway never replying to the search
+        let gateway = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).expect("bind");
+        let source = IGDBuilder::new()
+            .with_timeout(Duration::from_millis(100))
+            .with_bind_address(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+            .with_broadcast_address(gateway.local_addr().expect("local address"))
+            .build();
+        assert!(matches!(
+            block_on(source.get_ip(Family::IPv4)),
+            Err(Error::IgdSearch(_))
+        ));
+    }
+
+    #[test]
+    fn test_port_mapping() {
+        let (gateway, actions) = serve(None);
+        let local_addr = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 8080);
+        block_on(async {
+            let mapping = PortMapping::add(
+                gateway,
+                PortMappingProtocol::TCP,
+                8080,
+                local_addr,
+                Duration::from_secs(60),
+                "test",
+            )
+            .await
+            .expect("mapping added");
+            assert_eq!(mapping.external_port(), 8080);
+            let expires_at = mapping.expires_at().expect("lease expiration");
+
+            mapping.renew().await.expect("mapping renewed");
+            assert!(mapping.expires_at().expect("lease expiration") >= expires_at);
+            mapping.remove().await.expect("mapping removed");
+        });
+        let actions: Vec<_> = actions.try_iter().collect();
+        assert_eq!(
+            actions,
+            vec!["AddPortMapping", "AddPortMapping", "DeletePortMapping"]
+        );
+    }
+
+    #[test]
+    fn test_renew_automatically() {
+        let (gateway, actions) = serve(None);
+        let local_addr = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 8080);
+        block_on(async {
+            let mut mapping = PortMapping::add(
+                gateway,
+                PortMappingProtocol::TCP,
+                8080,
+                local_addr,
+                Duration::from_millis(200),
+                "test",
+            )
+            .await
+            .expect("mapping added");
+            assert_eq!(actions.try_iter().count(), 1);
+
+            // Renewed every 100ms
+            mapping.renew_automatically();
+            tokio::time::sleep(Duration::from_millis(350)).await;
+            assert!(actions.try_iter().count() >= 2);
+
+            // Dropping the mapping stops the renewal, once a request in flight is handled
+            drop(mapping);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let _ = actions.try_iter().count();
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            assert_eq!(actions.try_iter().count(), 0);
+        });
+    }
+
+    #[test]
+    fn test_port_mapping_any_port() {
+        let (gateway, _actions) = serve(None);
+        let local_addr = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 8080);
+        let mapping = block_on(PortMapping::add(
+            gateway,
+            PortMappingProtocol::UDP,
+            0,
+            local_addr,
+            Duration::ZERO,
+            "test",
+        ))
+        .expect("mapping added");
+        assert_eq!(mapping.external_port(), 4242);
+        assert_eq!(mapping.expires_at(), None);
+    }
+
+    #[test]
+    fn test_cached_gateway() {
+        let (gateway, actions) = serve(None);
+        // Stand-in multicast group never replying to the search
+        let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).expect("bind");
+        let source = IGDBuilder::new()
+            .with_timeout(Duration::from_millis(100))
+            .with_bind_address(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+            .with_broadcast_address(silent.local_addr().expect("local address"))
+            .build();
+        *source.gateway.lock().unwrap() = Some(gateway.clone());
+
+        let clone = source.clone();
+        let ip = block_on(clone.get_ip(Family::IPv4)).expect("valid reply");
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+        assert_eq!(actions.try_iter().count(), 1);
+
+        // The gateway moved away, the search runs again
+        let mut moved = gateway;
+        moved
+            .addr
+            .set_port(silent.local_addr().expect("local address").port());
+        *source.gateway.lock().unwrap() = Some(moved);
+        assert!(matches!(
+            block_on(source.get_ip(Family::IPv4)),
+            Err(Error::IgdSearch(_))
+        ));
+        assert!(source.gateway.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_cached_gateway_fault() {
+        let (gateway, actions) = serve(Some((718, "ConflictInMappingEntry")));
+        let source = IGDBuilder::new().build();
+        *source.gateway.lock().unwrap() = Some(gateway);
+
+        let local_addr = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 8080);
+        let result = block_on(source.add_port_mapping(
+            PortMappingProtocol::TCP,
+            8080,
+            local_addr,
+            Duration::from_secs(60),
+            "test",
+        ));
+        assert!(matches!(
+            result,
+            Err(Error::IgdAddPort(igd::AddPortError::PortInUse))
+        ));
+        // Refused by the gateway, so neither sent again nor searched again
+        let actions: Vec<_> = actions.try_iter().collect();
+        assert_eq!(actions, vec!["AddPortMapping"]);
+        assert!(source.gateway.lock().unwrap().is_some());
     }
 }
@@ -4,7 +4,7 @@ use std::net::IpAddr;
 use std::pin::Pin;
 
 /// IP Address family to try to resolve for
-#[derive(Copy, Clone, Debug, PartialEq, Default)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Default)]
 pub enum Family {
     /// Doesn't provide a specific IP family, so it will try all of them
     #[default]
@@ -19,22 +19,106 @@ pub enum Family {
 pub enum Error {
     #[error("HTTP request: {0}")]
     Http(#[from] reqwest::Error),
+    #[error("HTTP status {0}")]
+    HttpStatus(reqwest::StatusCode),
+    #[error("HTTP rate limited (status {status}), retry after {retry_after:?}")]
+    RateLimited {
+        status: reqwest::StatusCode,
+        retry_after: std::time::SystemTime,
+    },
+    #[error("HTTP content type {content_type:?} not accepted (status {status})")]
+    HttpContentType {
+        status: reqwest::StatusCode,
+        content_type: Option<String>,
+    },
+    #[error("HTTP body larger than {limit} bytes (status {status})")]
+    HttpBodyTooLarge {
+        status: reqwest::StatusCode,
+        limit: usize,
+    },
     #[error("Decode as UTF-8 failed: {0}")]
     DecodeError(#[from] std::str::Utf8Error),
     #[error("Address parsing: {0}")]
     InvalidAddress(#[from] std::net::AddrParseError),
+    #[error("TLS certificate does not match any pinned key")]
+    TlsPinMismatch,
     #[error("DNS resolution failed: {0}")]
     Dns(#[from] hickory_resolver::net::NetError),
     #[error("DNS resolution empty")]
     DnsResolutionEmpty,
     #[error("Unsupported family")]
     UnsupportedFamily,
+    #[cfg(feature = "dnssec")]
+    #[error("DNSSEC validation failed for {0}")]
+    DnssecValidation(String),
     #[cfg(feature = "igd")]
     #[error("IGD external IP: {0}")]
     IgdExternalIp(#[from] igd::GetExternalIpError),
     #[cfg(feature = "igd")]
     #[error("IGD search {0}")]
     IgdSearch(#[from] igd::SearchError),
+    #[cfg(feature = "igd")]
+    #[error("IGD add port mapping: {0}")]
+    IgdAddPort(#[from] igd::AddPortError),
+    #[cfg(feature = "igd")]
+    #[error("IGD add any port mapping: {0}")]
+    IgdAddAnyPort(#[from] igd::AddAnyPortError),
+    #[cfg(feature = "igd")]
+    #[error("IGD remove port mapping: {0}")]
+    IgdRemovePort(#[from] igd::RemovePortError),
+    #[error("Default gateway not found")]
+    GatewayNotFound,
+    #[cfg(feature = "discover_natpmp")]
+    #[error("NAT-PMP: {0}")]
+    NatPmpIo(std::io::Error),
+    #[cfg(feature = "discover_natpmp")]
+    #[error("NAT-PMP invalid reply")]
+    NatPmpInvalidReply,
+    #[cfg(feature = "discover_natpmp")]
+    #[error("NAT-PMP result code {0}")]
+    NatPmpResult(u16),
+    #[cfg(feature = "discover_pcp")]
+    #[error("PCP: {0}")]
+    PcpIo(std::io::Error),
+    #[cfg(feature = "discover_pcp")]
+    #[error("PCP invalid reply")]
+    PcpInvalidReply,
+    #[cfg(feature = "discover_pcp")]
+    #[error("PCP result code {0}")]
+    PcpResult(u8),
+    #[cfg(feature = "discover_tr064")]
+    #[error("TR-064 invalid reply")]
+    Tr064InvalidReply,
+    #[cfg(feature = "discover_openwrt")]
+    #[error("ubus status {0}")]
+    UbusStatus(i64),
+    #[cfg(feature = "discover_openwrt")]
+    #[error("ubus invalid reply")]
+    UbusInvalidReply,
+    #[cfg(feature = "discover_openwrt")]
+    #[error("ubus access denied")]
+    UbusAccessDenied,
+    #[cfg(feature = "discover_snmp")]
+    #[error("SNMP: {0}")]
+    SnmpIo(std::io::Error),
+    #[cfg(feature = "discover_snmp")]
+    #[error("SNMP invalid reply")]
+    SnmpInvalidReply,
+    #[cfg(feature = "discover_snmp")]
+    #[error("SNMP reply authentication failed")]
+    SnmpAuthentication,
+    #[cfg(feature = "discover_snmp")]
+    #[error("SNMP error status {0}")]
+    SnmpErrorStatus(i64),
+    #[cfg(feature = "discover_snmp")]
+    #[error("SNMP report {0}")]
+    SnmpReport(String),
+    #[cfg(feature = "discover_snmp")]
+    #[error("SNMP interface not found")]
+    SnmpInterfaceNotFound,
+    #[cfg(feature = "discover_snmp")]
+    #[error("SNMP interface has no address of the family")]
+    SnmpAddressNotFound,
 }
 
 pub type IpResult = Result<IpAddr, Error>;
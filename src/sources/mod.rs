@@ -1,4 +1,6 @@
+mod bind;
 mod dns;
+mod doh;
 mod http;
 
 #[cfg(feature = "igd")]
@@ -6,24 +8,98 @@ mod igd;
 
 mod interfaces;
 
-pub use self::dns::{DNSSource, QueryType, get_dns_sources};
-pub use self::http::{HTTPSource, HTTPSourceBuilder, get_http_sources};
+#[cfg(any(
+    feature = "discover_natpmp",
+    feature = "discover_pcp",
+    feature = "discover_snmp"
+))]
+mod gateway;
+#[cfg(feature = "discover_natpmp")]
+mod natpmp;
+#[cfg(feature = "discover_openwrt")]
+mod openwrt;
+#[cfg(feature = "discover_pcp")]
+mod pcp;
+#[cfg(feature = "discover_snmp")]
+mod snmp;
+#[cfg(feature = "discover_tr064")]
+mod tr064;
+
+#[cfg(test)]
+mod test_util;
+
+pub use self::dns::{
+    BootstrapResolver, DNSSource, DNSSourceBuilder, ExtractFn, QueryType, Transport, TxtExtractor,
+    get_dns_sources, get_dns_sources_with_bootstrap, get_resolver_egress_sources,
+};
+pub use self::doh::DoHSource;
+pub use self::http::{DEFAULT_MAX_BODY_SIZE, HTTPSource, HTTPSourceBuilder, get_http_sources};
+#[cfg(feature = "igd")]
+pub use self::igd::{IGD, IGDBuilder, PortMapping};
 #[cfg(feature = "igd")]
-pub use self::igd::IGD;
+pub use ::igd::PortMappingProtocol;
 pub use interfaces::*;
+#[cfg(feature = "discover_natpmp")]
+pub use natpmp::{NatPmp, NatPmpBuilder};
+#[cfg(feature = "discover_openwrt")]
+pub use openwrt::{OpenWrt, OpenWrtBuilder};
+#[cfg(feature = "discover_pcp")]
+pub use pcp::{Pcp, PcpBuilder};
+#[cfg(feature = "discover_snmp")]
+pub use snmp::{Snmp, SnmpAuthProtocol, SnmpBuilder, SnmpInterface, SnmpPrivProtocol, SnmpV3User};
+#[cfg(feature = "discover_tr064")]
+pub use tr064::{Tr064, Tr064Builder};
+
+/// Returns a collection of the sources reporting the WAN address of the nearest gateway itself
+/// (IGD and NAT-PMP), among the ones enabled by the features
+///
+/// PCP is left out, as its reply is the external address of the outermost NAT.
+pub fn get_local_gateway_sources<T>() -> T
+where
+    T: std::iter::FromIterator<Box<dyn Source>>,
+{
+    let sources: Vec<Box<dyn Source>> = vec![
+        #[cfg(feature = "igd")]
+        IGD::source(),
+        #[cfg(feature = "discover_natpmp")]
+        NatPmp::source(),
+    ];
+    sources.into_iter().collect()
+}
+
+/// Returns a collection of the sources asking the local gateway for its WAN address, among the
+/// ones enabled by the features
+///
+/// TR-064 is left out, its default control URL only exists on a Fritz!Box (see `Tr064::source`).
+pub fn get_gateway_sources<T>() -> T
+where
+    T: std::iter::FromIterator<Box<dyn Source>>,
+{
+    let local: Vec<_> = get_local_gateway_sources();
+    let sources: Vec<Box<dyn Source>> = vec![
+        #[cfg(feature = "discover_pcp")]
+        Pcp::source(),
+    ];
+    local.into_iter().chain(sources).collect()
+}
 
 /// Returns a collection of all possible sources
 pub fn get_sources<T>() -> T
 where
     T: std::iter::FromIterator<Box<dyn Source>>,
 {
-    let sources = if cfg!(feature = "igd") {
-        Some(IGD::source())
-    } else {
-        None
-    };
+    get_sources_with_bootstrap(BootstrapResolver::default())
+}
+
+/// Returns a collection of all possible sources, the DNS ones looking up their nameservers with
+/// the given bootstrap resolver
+pub fn get_sources_with_bootstrap<T>(bootstrap: BootstrapResolver) -> T
+where
+    T: std::iter::FromIterator<Box<dyn Source>>,
+{
+    let sources: Vec<_> = get_gateway_sources();
 
-    let d: Vec<_> = get_dns_sources();
+    let d: Vec<_> = get_dns_sources_with_bootstrap(bootstrap);
     let h: Vec<_> = get_http_sources();
 
     let sources = sources.into_iter().chain(d.into_iter().chain(h));
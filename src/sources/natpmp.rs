@@ -0,0 +1,173 @@
+use crate::sources::gateway;
+use crate::sources::interfaces::{Error, Family, IpFuture, IpResult, Source};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use log::trace;
+
+/// Port of the NAT-PMP server on the gateway
+const NATPMP_PORT: u16 = 5351;
+
+/// First retransmission interval, doubled on every retry (RFC 6886 section 3.1)
+const INITIAL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Length of the longest reply (the mapping reply, RFC 6886 section 3.3)
+const MAX_REPLY_SIZE: usize = 16;
+
+pub struct NatPmpBuilder {
+    gateway: Option<SocketAddr>,
+    timeout: Duration,
+}
+
+impl NatPmpBuilder {
+    pub fn new() -> Self {
+        Self {
+            gateway: None,
+            timeout: Duration::from_secs(4),
+        }
+    }
+    /// Sets the address of the NAT-PMP server, the default gateway on port 5351 otherwise
+    pub fn with_gateway(mut self, gateway: SocketAddr) -> Self {
+        self.gateway = Some(gateway);
+        self
+    }
+    /// Sets how long to retransmit the request before giving up
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    pub fn build(self) -> NatPmp {
+        let Self { gateway, timeout } = self;
+        NatPmp { gateway, timeout }
+    }
+}
+
+impl Default for NatPmpBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// NAT-PMP Source of the external ip
+///
+/// It asks the gateway for its external address with the NAT-PMP (RFC 6886) request of opcode 0.
+///
+/// The default gateway is only discovered on Linux, elsewhere it must be set in the builder.
+///
+/// The feature "discover_natpmp" must be enabled to use this (off by default)
+#[derive(Debug, Clone)]
+pub struct NatPmp {
+    gateway: Option<SocketAddr>,
+    timeout: Duration,
+}
+
+impl NatPmp {
+    pub fn source() -> Box<dyn Source> {
+        Box::new(NatPmpBuilder::new().build())
+    }
+
+    fn gateway(&self) -> Result<SocketAddr, Error> {
+        match self.gateway {
+            Some(gateway) => Ok(gateway),
+            None => {
+                gateway::default_gateway_address(false, NATPMP_PORT).ok_or(Error::GatewayNotFound)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for NatPmp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NAT-PMP")
+    }
+}
+
+/// Parses the reply to the external address request
+fn parse_reply(reply: &[u8]) -> IpResult {
+    if reply.len() < 12 || reply[0] != 0 || reply[1] != 128 {
+        return Err(Error::NatPmpInvalidReply);
+    }
+    let result = u16::from_be_bytes([reply[2], reply[3]]);
+    if result != 0 {
+        return Err(Error::NatPmpResult(result));
+    }
+    let ip = Ipv4Addr::new(reply[8], reply[9], reply[10], reply[11]);
+    Ok(IpAddr::V4(ip))
+}
+
+impl Source for NatPmp {
+    fn get_ip(&self, family: Family) -> IpFuture<'_> {
+        async fn run(_self: &NatPmp, family: Family) -> IpResult {
+            if !matches!(family, Family::IPv4 | Family::Any) {
+                return Err(Error::UnsupportedFamily);
+            }
+            let gateway = _self.gateway()?;
+            trace!("Contacting NAT-PMP gateway {}", gateway);
+            let socket = gateway::connect(gateway).await.map_err(Error::NatPmpIo)?;
+            let reply = gateway::exchange(
+                &socket,
+                &[0, 0],
+                MAX_REPLY_SIZE,
+                INITIAL_INTERVAL,
+                _self.timeout,
+            )
+            .await
+            .map_err(Error::NatPmpIo)?;
+            parse_reply(&reply)
+        }
+        Box::pin(run(self, family))
+    }
+
+    fn box_clone(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::test_util::serve_udp;
+    use std::io;
+    use tokio_test::block_on;
+
+    /// Runs a stand-in NAT-PMP server on a loopback port replying with `reply`, after ignoring
+    /// the first `drop` requests
+    fn serve(reply: Vec<u8>, drop: usize) -> SocketAddr {
+        let mut received = 0;
+        serve_udp(IpAddr::V4(Ipv4Addr::LOCALHOST), move |request| {
+            assert_eq!(request, &[0, 0]);
+            received += 1;
+            (received > drop).then(|| reply.clone())
+        })
+    }
+
+    #[test]
+    fn test_external_address() {
+        let reply = vec![0, 128, 0, 0, 0, 0, 0, 1, 1, 2, 3, 4];
+        let source = NatPmpBuilder::new().with_gateway(serve(reply, 1)).build();
+        let ip = block_on(source.get_ip(Family::Any)).expect("valid reply");
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+    }
+
+    #[test]
+    fn test_result_code() {
+        let reply = vec![0, 128, 0, 3, 0, 0, 0, 1, 0, 0, 0, 0];
+        let source = NatPmpBuilder::new().with_gateway(serve(reply, 0)).build();
+        assert!(matches!(
+            block_on(source.get_ip(Family::IPv4)),
+            Err(Error::NatPmpResult(3))
+        ));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let source = NatPmpBuilder::new()
+            .with_gateway(serve(Vec::new(), usize::MAX))
+            .with_timeout(Duration::from_millis(100))
+            .build();
+        assert!(matches!(
+            block_on(source.get_ip(Family::IPv4)),
+            Err(Error::NatPmpIo(err)) if err.kind() == io::ErrorKind::TimedOut
+        ));
+    }
+}
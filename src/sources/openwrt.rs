@@ -0,0 +1,367 @@
+use crate::sources::http::read_body;
+use crate::sources::interfaces::{Error, Family, IpFuture, IpResult, Source};
+use log::trace;
+use serde_json::{Value, json};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Session ID used for the login call
+const NULL_SESSION: &str = "00000000000000000000000000000000";
+/// JSON-RPC error code of rpcd for an unknown or expired session
+const ACCESS_DENIED: i64 = -32002;
+/// Largest JSON-RPC reply accepted
+const MAX_REPLY_SIZE: usize = 64 * 1024;
+
+pub struct OpenWrtBuilder {
+    url: String,
+    username: String,
+    password: String,
+    ipv4_interface: String,
+    ipv6_interface: String,
+    timeout: Duration,
+}
+
+impl OpenWrtBuilder {
+    /// Creates a builder for the router at `url` (e.g. `http://192.168.1.1`)
+    pub fn new<S, U, P>(url: S, username: U, password: P) -> Self
+    where
+        S: Into<String>,
+        U: Into<String>,
+        P: Into<String>,
+    {
+        Self {
+            url: url.into(),
+            username: username.into(),
+            password: password.into(),
+            ipv4_interface: String::from("wan"),
+            ipv6_interface: String::from("wan6"),
+            timeout: Duration::from_secs(5),
+        }
+    }
+    /// Sets the logical interface to read the IPv4 address from (`wan` by default)
+    pub fn with_ipv4_interface<S: Into<String>>(mut self, interface: S) -> Self {
+        self.ipv4_interface = interface.into();
+        self
+    }
+    /// Sets the logical interface to read the IPv6 address from (`wan6` by default)
+    pub fn with_ipv6_interface<S: Into<String>>(mut self, interface: S) -> Self {
+        self.ipv6_interface = interface.into();
+        self
+    }
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    pub fn build(self) -> OpenWrt {
+        let Self {
+            url,
+            username,
+            password,
+            ipv4_interface,
+            ipv6_interface,
+            timeout,
+        } = self;
+        OpenWrt {
+            url,
+            username,
+            password,
+            ipv4_interface,
+            ipv6_interface,
+            timeout,
+            session: Arc::default(),
+        }
+    }
+}
+
+/// OpenWrt Source of the external ip
+///
+/// It logs into the rpcd JSON-RPC endpoint (`/ubus`) of an OpenWrt router and reads the address
+/// of the WAN interface from `network.interface.<name> status`.
+///
+/// The user needs read access to `network.interface` in the rpcd ACLs.
+///
+/// The rpcd session is reused, also by clones of the source, and renewed once rpcd denies access
+/// with it (e.g. after it expired).
+///
+/// The feature "discover_openwrt" must be enabled to use this (off by default)
+#[derive(Clone)]
+pub struct OpenWrt {
+    url: String,
+    username: String,
+    password: String,
+    ipv4_interface: String,
+    ipv6_interface: String,
+    timeout: Duration,
+    session: Arc<Mutex<Option<String>>>,
+}
+
+impl std::fmt::Debug for OpenWrt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OpenWrt")
+            .field("url", &self.url)
+            .field("username", &self.username)
+            .field("ipv4_interface", &self.ipv4_interface)
+            .field("ipv6_interface", &self.ipv6_interface)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl std::fmt::Display for OpenWrt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "OpenWrt: {}", self.url)
+    }
+}
+
+impl OpenWrt {
+    fn endpoint(&self) -> String {
+        format!("{}/ubus", self.url.trim_end_matches('/'))
+    }
+
+    /// Calls the ubus method, returning the data of the reply
+    async fn call(
+        &self,
+        client: &reqwest::Client,
+        session: &str,
+        object: &str,
+        method: &str,
+        args: Value,
+    ) -> Result<Value, Error> {
+        trace!("Calling ubus {} {} on {}", object, method, self.url);
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "call",
+            "params": [session, object, method, args],
+        });
+        let resp = client.post(self.endpoint()).json(&request).send().await?;
+        if !resp.status().is_success() {
+            return Err(Error::HttpStatus(resp.status()));
+        }
+        let body = read_body(resp, MAX_REPLY_SIZE).await?;
+        let reply: Value = serde_json::from_slice(&body).map_err(|_| Error::UbusInvalidReply)?;
+        if let Some(error) = reply.get("error") {
+            return match error.get("code").and_then(Value::as_i64) {
+                Some(ACCESS_DENIED) => Err(Error::UbusAccessDenied),
+                _ => Err(Error::UbusInvalidReply),
+            };
+        }
+        let result = reply.get("result").ok_or(Error::UbusInvalidReply)?;
+        match result.get(0).and_then(Value::as_i64) {
+            Some(0) => Ok(result.get(1).cloned().unwrap_or(Value::Null)),
+            Some(status) => Err(Error::UbusStatus(status)),
+            None => Err(Error::UbusInvalidReply),
+        }
+    }
+
+    /// Logs in, returning the ID of the new session
+    async fn login(&self, client: &reqwest::Client) -> Result<String, Error> {
+        let login = self
+            .call(
+                client,
+                NULL_SESSION,
+                "session",
+                "login",
+                json!({ "username": self.username, "password": self.password }),
+            )
+            .await?;
+        let session = login
+            .get("ubus_rpc_session")
+            .and_then(Value::as_str)
+            .ok_or(Error::UbusInvalidReply)?;
+        Ok(session.to_string())
+    }
+
+    /// Calls the ubus method with the cached session, logging in again if rpcd denies access
+    async fn call_with_session(
+        &self,
+        client: &reqwest::Client,
+        object: &str,
+        method: &str,
+        args: Value,
+    ) -> Result<Value, Error> {
+        let cached = self.session.lock().unwrap().clone();
+        if let Some(session) = cached {
+            trace!("Reusing ubus session on {}", self.url);
+            match self
+                .call(client, &session, object, method, args.clone())
+                .await
+            {
+                Err(Error::UbusAccessDenied) => {
+                    trace!("ubus session on {} expired", self.url);
+                    self.session.lock().unwrap().take();
+                }
+                result => return result,
+            }
+        }
+        let session = self.login(client).await?;
+        *self.session.lock().unwrap() = Some(session.clone());
+        self.call(client, &session, object, method, args).await
+    }
+}
+
+/// Returns the first address in the list of the interface status (`ipv4-address` or
+/// `ipv6-address`)
+fn interface_address(status: &Value, key: &str) -> Option<IpAddr> {
+    status
+        .get(key)?
+        .as_array()?
+        .iter()
+        .find_map(|address| address.get("address")?.as_str()?.parse().ok())
+}
+
+impl Source for OpenWrt {
+    fn get_ip(&self, family: Family) -> IpFuture<'_> {
+        async fn run(_self: &OpenWrt, family: Family) -> IpResult {
+            let (interface, key) = match family {
+                Family::IPv4 | Family::Any => (&_self.ipv4_interface, "ipv4-address"),
+                Family::IPv6 => (&_self.ipv6_interface, "ipv6-address"),
+            };
+            let client = reqwest::Client::builder().timeout(_self.timeout).build()?;
+
+            let object = format!("network.interface.{}", interface);
+            let status = _self
+                .call_with_session(&client, &object, "status", json!({}))
+                .await?;
+            interface_address(&status, key).ok_or(Error::UbusInvalidReply)
+        }
+        Box::pin(run(self, family))
+    }
+
+    fn box_clone(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::sources::test_util::{response, serve_http};
+    use std::net::Ipv4Addr;
+    use tokio_test::block_on;
+
+    /// Sessions handed out by the stand-in rpcd, and the one still valid
+    #[derive(Default)]
+    struct Sessions {
+        logins: usize,
+        valid: Option<String>,
+    }
+
+    /// Runs a stand-in rpcd endpoint on a loopback port accepting `root` with `secret`, and
+    /// returns its URL
+    fn serve() -> (String, Arc<Mutex<Sessions>>) {
+        let sessions = Arc::new(Mutex::new(Sessions::default()));
+        let state = sessions.clone();
+        let addr = serve_http(move |request| {
+            assert!(request.head.starts_with("POST /ubus "));
+            let call: Value = serde_json::from_slice(&request.body).expect("JSON");
+            let params = &call["params"];
+            let mut sessions = state.lock().unwrap();
+            let session = params[0].as_str();
+            let result = match (session, params[1].as_str(), params[2].as_str()) {
+                (Some(NULL_SESSION), Some("session"), Some("login")) => {
+                    if params[3] == json!({ "username": "root", "password": "secret" }) {
+                        sessions.logins += 1;
+                        let session = format!("session-{}", sessions.logins);
+                        sessions.valid = Some(session.clone());
+                        json!([0, { "ubus_rpc_session": session, "timeout": 300 }])
+                    } else {
+                        json!([6])
+                    }
+                }
+                _ if session != sessions.valid.as_deref() => Value::Null,
+                (_, Some("network.interface.wan"), Some("status")) => json!([0, {
+                    "up": true,
+                    "ipv4-address": [{ "address": "1.2.3.4", "mask": 24 }],
+                }]),
+                (_, Some("network.interface.wan6"), Some("status")) => json!([0, {
+                    "up": true,
+                    "ipv6-address": [{ "address": "2001:db8::1", "mask": 64 }],
+                }]),
+                _ => json!([6]),
+            };
+            drop(sessions);
+            let reply = if result.is_null() {
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": call["id"],
+                    "error": { "code": ACCESS_DENIED, "message": "Access denied" },
+                })
+            } else {
+                json!({ "jsonrpc": "2.0", "id": call["id"], "result": result })
+            };
+            let body = reply.to_string();
+            response(
+                "200 OK",
+                "Content-Type: application/json\r\n",
+                body.as_bytes(),
+            )
+        });
+        (format!("http://{}", addr), sessions)
+    }
+
+    #[test]
+    fn test_wan_addresses() {
+        let (url, _) = serve();
+        let source = OpenWrtBuilder::new(url, "root", "secret").build();
+        let ip = block_on(source.get_ip(Family::Any)).expect("valid reply");
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+        let ip = block_on(source.get_ip(Family::IPv6)).expect("valid reply");
+        assert_eq!(ip, "2001:db8::1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_session_reused() {
+        let (url, sessions) = serve();
+        let source = OpenWrtBuilder::new(url, "root", "secret").build();
+        for _ in 0..3 {
+            block_on(source.get_ip(Family::IPv4)).expect("valid reply");
+        }
+        block_on(source.clone().get_ip(Family::IPv6)).expect("valid reply");
+        assert_eq!(sessions.lock().unwrap().logins, 1);
+
+        // The session expired on the router, the source logs in again once
+        sessions.lock().unwrap().valid = None;
+        block_on(source.get_ip(Family::IPv4)).expect("valid reply");
+        block_on(source.get_ip(Family::IPv4)).expect("valid reply");
+        assert_eq!(sessions.lock().unwrap().logins, 2);
+    }
+
+    #[test]
+    fn test_permission_denied() {
+        let (url, _) = serve();
+        let source = OpenWrtBuilder::new(url, "root", "wrong").build();
+        assert!(matches!(
+            block_on(source.get_ip(Family::IPv4)),
+            Err(Error::UbusStatus(6))
+        ));
+
+        let (url, _) = serve();
+        let source = OpenWrtBuilder::new(url, "root", "secret")
+            .with_ipv4_interface("lan")
+            .build();
+        assert!(matches!(
+            block_on(source.get_ip(Family::IPv4)),
+            Err(Error::UbusStatus(6))
+        ));
+    }
+
+    #[test]
+    fn test_reply_too_large() {
+        let addr = serve_http(|_| {
+            let body = format!("\"{}\"", " ".repeat(MAX_REPLY_SIZE));
+            response(
+                "200 OK",
+                "Content-Type: application/json\r\n",
+                body.as_bytes(),
+            )
+        });
+        let source = OpenWrtBuilder::new(format!("http://{}", addr), "root", "secret").build();
+        assert!(matches!(
+            block_on(source.get_ip(Family::IPv4)),
+            Err(Error::HttpBodyTooLarge { .. })
+        ));
+    }
+}
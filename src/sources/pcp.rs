@@ -0,0 +1,269 @@
+use crate::sources::gateway;
+use crate::sources::interfaces::{Error, Family, IpFuture, IpResult, Source};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use log::trace;
+
+/// Port of the PCP server on the gateway
+const PCP_PORT: u16 = 5351;
+
+/// Initial retransmission interval, doubled on every retry (RFC 6887 section 8.1.1)
+const INITIAL_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Lifetime requested for the mapping, deleted as soon as the reply is received
+const MAPPING_LIFETIME: u32 = 120;
+
+const PCP_VERSION: u8 = 2;
+const OPCODE_MAP: u8 = 1;
+const RESPONSE_BIT: u8 = 0x80;
+const PROTOCOL_UDP: u8 = 17;
+const MAP_SIZE: usize = 60;
+
+/// Largest PCP message (RFC 6887 section 7)
+const MAX_MESSAGE_SIZE: usize = 1100;
+
+pub struct PcpBuilder {
+    gateway: Option<SocketAddr>,
+    timeout: Duration,
+}
+
+impl PcpBuilder {
+    pub fn new() -> Self {
+        Self {
+            gateway: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+    /// Sets the address of the PCP server, the default gateway of the family on port 5351
+    /// otherwise
+    pub fn with_gateway(mut self, gateway: SocketAddr) -> Self {
+        self.gateway = Some(gateway);
+        self
+    }
+    /// Sets how long to retransmit the request before giving up
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    pub fn build(self) -> Pcp {
+        let Self { gateway, timeout } = self;
+        Pcp { gateway, timeout }
+    }
+}
+
+impl Default for PcpBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// PCP Source of the external ip
+///
+/// It requests a short lived UDP mapping with the Port Control Protocol (RFC 6887) MAP opcode,
+/// and reports the assigned external address. The mapping is deleted right after.
+///
+/// Works with carrier-grade NATs and IPv6 firewalls implementing PCP. The default gateway is only
+/// discovered on Linux, elsewhere it must be set in the builder.
+///
+/// The feature "discover_pcp" must be enabled to use this (off by default)
+#[derive(Debug, Clone)]
+pub struct Pcp {
+    gateway: Option<SocketAddr>,
+    timeout: Duration,
+}
+
+impl Pcp {
+    pub fn source() -> Box<dyn Source> {
+        Box::new(PcpBuilder::new().build())
+    }
+
+    fn gateway(&self, family: Family) -> Result<SocketAddr, Error> {
+        match (self.gateway, family) {
+            (Some(gateway), Family::Any) => Ok(gateway),
+            (Some(gateway), Family::IPv4) if gateway.is_ipv4() => Ok(gateway),
+            (Some(gateway), Family::IPv6) if gateway.is_ipv6() => Ok(gateway),
+            (Some(_), _) => Err(Error::UnsupportedFamily),
+            (None, Family::IPv4) => {
+                gateway::default_gateway_address(false, PCP_PORT).ok_or(Error::GatewayNotFound)
+            }
+            (None, Family::IPv6) => {
+                gateway::default_gateway_address(true, PCP_PORT).ok_or(Error::GatewayNotFound)
+            }
+            (None, Family::Any) => gateway::default_gateway_address(false, PCP_PORT)
+                .or_else(|| gateway::default_gateway_address(true, PCP_PORT))
+                .ok_or(Error::GatewayNotFound),
+        }
+    }
+}
+
+impl std::fmt::Display for Pcp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PCP")
+    }
+}
+
+/// Returns a random nonce identifying the mapping
+fn nonce() -> [u8; 12] {
+    rand::random()
+}
+
+/// Returns the address in the 128 bits format of PCP, IPv4 addresses are mapped in IPv6
+fn pcp_address(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+/// Builds a MAP request for UDP from the client address and port
+fn map_request(client: SocketAddr, lifetime: u32, nonce: &[u8; 12]) -> Vec<u8> {
+    let suggested = match client {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let mut request = Vec::with_capacity(MAP_SIZE);
+    request.extend_from_slice(&[PCP_VERSION, OPCODE_MAP, 0, 0]);
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    request.extend_from_slice(&pcp_address(client.ip()));
+    request.extend_from_slice(nonce);
+    request.extend_from_slice(&[PROTOCOL_UDP, 0, 0, 0]);
+    request.extend_from_slice(&client.port().to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&pcp_address(suggested));
+    request
+}
+
+/// Parses the reply to the MAP request, returning the assigned external address
+fn parse_reply(reply: &[u8], nonce: &[u8; 12]) -> IpResult {
+    if reply.len() < MAP_SIZE
+        || reply[0] != PCP_VERSION
+        || reply[1] != RESPONSE_BIT | OPCODE_MAP
+        || &reply[24..36] != nonce
+    {
+        return Err(Error::PcpInvalidReply);
+    }
+    if reply[3] != 0 {
+        return Err(Error::PcpResult(reply[3]));
+    }
+    let mut octets = [0; 16];
+    octets.copy_from_slice(&reply[44..60]);
+    let ip = Ipv6Addr::from(octets);
+    Ok(match ip.to_ipv4_mapped() {
+        Some(ip) => IpAddr::V4(ip),
+        None => IpAddr::V6(ip),
+    })
+}
+
+impl Source for Pcp {
+    fn get_ip(&self, family: Family) -> IpFuture<'_> {
+        async fn run(_self: &Pcp, family: Family) -> IpResult {
+            let gateway = _self.gateway(family)?;
+            trace!("Contacting PCP gateway {}", gateway);
+            let socket = gateway::connect(gateway).await.map_err(Error::PcpIo)?;
+            let client = socket.local_addr().map_err(Error::PcpIo)?;
+
+            let nonce = nonce();
+            let request = map_request(client, MAPPING_LIFETIME, &nonce);
+            let reply = gateway::exchange(
+                &socket,
+                &request,
+                MAX_MESSAGE_SIZE,
+                INITIAL_INTERVAL,
+                _self.timeout,
+            )
+            .await
+            .map_err(Error::PcpIo)?;
+            let result = parse_reply(&reply, &nonce);
+
+            if result.is_ok() {
+                // Best effort, the mapping expires anyway
+                let delete = map_request(client, 0, &nonce);
+                if let Err(err) = socket.send(&delete).await {
+                    trace!("PCP mapping deletion failed: {}", err);
+                }
+            }
+            result
+        }
+        Box::pin(run(self, family))
+    }
+
+    fn box_clone(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::test_util::serve_udp;
+    use std::sync::mpsc;
+    use tokio_test::block_on;
+
+    /// Runs a stand-in PCP server on a loopback port assigning `external` with the given result
+    /// code, and returns its address and the requests it received
+    fn serve(ip: IpAddr, external: IpAddr, result: u8) -> (SocketAddr, mpsc::Receiver<Vec<u8>>) {
+        let (tx, rx) = mpsc::channel();
+        let address = serve_udp(ip, move |request| {
+            let mut reply = request.to_vec();
+            reply[1] |= RESPONSE_BIT;
+            reply[3] = result;
+            reply[8..24].fill(0);
+            reply[42..44].copy_from_slice(&4321u16.to_be_bytes());
+            reply[44..60].copy_from_slice(&pcp_address(external));
+            let _ = tx.send(request.to_vec());
+            Some(reply)
+        });
+        (address, rx)
+    }
+
+    #[test]
+    fn test_ipv4() {
+        let external = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        let (gateway, requests) = serve(IpAddr::V4(Ipv4Addr::LOCALHOST), external, 0);
+        let source = PcpBuilder::new().with_gateway(gateway).build();
+        assert_eq!(
+            block_on(source.get_ip(Family::IPv4)).expect("valid reply"),
+            external
+        );
+
+        let map = requests.recv().expect("MAP request");
+        assert_eq!(map.len(), MAP_SIZE);
+        assert_eq!(&map[..2], &[PCP_VERSION, OPCODE_MAP]);
+        assert_eq!(&map[4..8], &MAPPING_LIFETIME.to_be_bytes());
+        assert_eq!(&map[8..24], &pcp_address(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        let delete = requests.recv().expect("delete request");
+        assert_eq!(&delete[4..8], &0u32.to_be_bytes());
+        assert_eq!(&delete[24..36], &map[24..36]);
+    }
+
+    #[test]
+    fn test_ipv6() {
+        // Hosts without an IPv6 loopback can't run the stand-in gateway
+        if std::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).is_err() {
+            return;
+        }
+        let external: IpAddr = "2001:db8::1".parse().unwrap();
+        let (gateway, _requests) = serve(IpAddr::V6(Ipv6Addr::LOCALHOST), external, 0);
+        let source = PcpBuilder::new().with_gateway(gateway).build();
+        assert_eq!(
+            block_on(source.get_ip(Family::IPv6)).expect("valid reply"),
+            external
+        );
+        assert!(matches!(
+            block_on(source.get_ip(Family::IPv4)),
+            Err(Error::UnsupportedFamily)
+        ));
+    }
+
+    #[test]
+    fn test_result_code() {
+        let external = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+        let (gateway, _requests) = serve(IpAddr::V4(Ipv4Addr::LOCALHOST), external, 2);
+        let source = PcpBuilder::new().with_gateway(gateway).build();
+        assert!(matches!(
+            block_on(source.get_ip(Family::Any)),
+            Err(Error::PcpResult(2))
+        ));
+    }
+}
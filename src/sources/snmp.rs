@@ -0,0 +1,1316 @@
+use crate::sources::gateway;
+use crate::sources::interfaces::{Error, Family, IpFuture, IpResult, Source};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use aes::Aes128;
+use cfb_mode::cipher::{AsyncStreamCipher, KeyIvInit};
+use hmac::{Hmac, Mac};
+use log::trace;
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use sha2::Sha256;
+use tokio::net::UdpSocket;
+
+/// Port of the SNMP agent
+const SNMP_PORT: u16 = 161;
+
+/// First retransmission interval, doubled on every retry
+const INITIAL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Rows asked in every GetBulk request
+const MAX_REPETITIONS: i64 = 16;
+
+/// Largest message accepted in reply, the largest UDP payload over IPv4
+const MAX_MESSAGE_SIZE: usize = 65507;
+
+/// IF-MIB::ifName, indexed by ifIndex
+const IF_NAME: &[u32] = &[1, 3, 6, 1, 2, 1, 31, 1, 1, 1, 1];
+/// IP-MIB::ipAddressIfIndex, indexed by address type and address (RFC 4293)
+const IP_ADDRESS_IF_INDEX: &[u32] = &[1, 3, 6, 1, 2, 1, 4, 34, 1, 3];
+/// IP-MIB::ipAdEntIfIndex, indexed by IPv4 address (deprecated, but the only table of older
+/// agents)
+const IP_AD_ENT_IF_INDEX: &[u32] = &[1, 3, 6, 1, 2, 1, 4, 20, 1, 2];
+/// SNMP-USER-BASED-SM-MIB::usmStatsNotInTimeWindows.0
+const USM_STATS_NOT_IN_TIME_WINDOWS: &[u32] = &[1, 3, 6, 1, 6, 3, 15, 1, 1, 2, 0];
+
+// BER tags
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const NULL: u8 = 0x05;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const SEQUENCE: u8 = 0x30;
+const END_OF_MIB_VIEW: u8 = 0x82;
+
+// PDU types
+const GET_REQUEST: u8 = 0xa0;
+const RESPONSE: u8 = 0xa2;
+const GET_BULK_REQUEST: u8 = 0xa5;
+const REPORT: u8 = 0xa8;
+
+// SNMPv3 message flags
+const FLAG_AUTH: u8 = 0x01;
+const FLAG_PRIV: u8 = 0x02;
+const FLAG_REPORTABLE: u8 = 0x04;
+
+/// Security model number of the user-based security model
+const USM: i64 = 3;
+
+/// Interface of the router holding the WAN address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnmpInterface {
+    /// IF-MIB ifIndex of the interface
+    Index(u32),
+    /// IF-MIB ifName of the interface (e.g. `pppoe-wan`)
+    Name(String),
+}
+
+/// SNMPv3 authentication protocol
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SnmpAuthProtocol {
+    /// HMAC-MD5-96 (RFC 3414)
+    Md5,
+    /// HMAC-SHA-96 (RFC 3414)
+    Sha1,
+    /// HMAC-SHA-256-192 (RFC 7860)
+    Sha256,
+}
+
+/// SNMPv3 privacy protocol
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SnmpPrivProtocol {
+    /// AES-128 in CFB mode (RFC 3826)
+    Aes128,
+}
+
+/// SNMPv3 user of the user-based security model (RFC 3414)
+#[derive(Clone)]
+pub struct SnmpV3User {
+    username: String,
+    auth: Option<(SnmpAuthProtocol, String)>,
+    privacy: Option<(SnmpPrivProtocol, String)>,
+}
+
+impl SnmpV3User {
+    /// User without authentication nor privacy (noAuthNoPriv)
+    pub fn new<U: Into<String>>(username: U) -> Self {
+        Self {
+            username: username.into(),
+            auth: None,
+            privacy: None,
+        }
+    }
+    /// User with authenticated messages (authNoPriv)
+    pub fn with_auth<U: Into<String>, P: Into<String>>(
+        username: U,
+        protocol: SnmpAuthProtocol,
+        password: P,
+    ) -> Self {
+        Self {
+            auth: Some((protocol, password.into())),
+            ..Self::new(username)
+        }
+    }
+    /// User with authenticated and encrypted messages (authPriv)
+    pub fn with_auth_priv<U, A, P>(
+        username: U,
+        auth_protocol: SnmpAuthProtocol,
+        auth_password: A,
+        priv_protocol: SnmpPrivProtocol,
+        priv_password: P,
+    ) -> Self
+    where
+        U: Into<String>,
+        A: Into<String>,
+        P: Into<String>,
+    {
+        Self {
+            privacy: Some((priv_protocol, priv_password.into())),
+            ..Self::with_auth(username, auth_protocol, auth_password)
+        }
+    }
+}
+
+impl std::fmt::Debug for SnmpV3User {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SnmpV3User")
+            .field("username", &self.username)
+            .field("auth", &self.auth.as_ref().map(|(protocol, _)| protocol))
+            .field(
+                "privacy",
+                &self.privacy.as_ref().map(|(protocol, _)| protocol),
+            )
+            .finish()
+    }
+}
+
+/// Credentials used to query the agent
+#[derive(Clone)]
+enum Security {
+    Community(String),
+    User(SnmpV3User),
+}
+
+impl std::fmt::Debug for Security {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Security::Community(_) => write!(f, "Community"),
+            Security::User(user) => user.fmt(f),
+        }
+    }
+}
+
+pub struct SnmpBuilder {
+    agent: Option<SocketAddr>,
+    interface: SnmpInterface,
+    security: Security,
+    timeout: Duration,
+}
+
+impl SnmpBuilder {
+    /// Creates a builder reading the addresses of `interface`
+    pub fn new(interface: SnmpInterface) -> Self {
+        Self {
+            agent: None,
+            interface,
+            security: Security::Community(String::from("public")),
+            timeout: Duration::from_secs(4),
+        }
+    }
+    /// Sets the address of the SNMP agent, the default gateway on port 161 otherwise
+    pub fn with_agent(mut self, agent: SocketAddr) -> Self {
+        self.agent = Some(agent);
+        self
+    }
+    /// Queries the agent with SNMPv2c and the given community (`public` by default)
+    pub fn with_community<S: Into<String>>(mut self, community: S) -> Self {
+        self.security = Security::Community(community.into());
+        self
+    }
+    /// Queries the agent with SNMPv3 as the given user
+    pub fn with_v3_user(mut self, user: SnmpV3User) -> Self {
+        self.security = Security::User(user);
+        self
+    }
+    /// Sets how long to retransmit every request before giving up
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    pub fn build(self) -> Snmp {
+        let Self {
+            agent,
+            interface,
+            security,
+            timeout,
+        } = self;
+        Snmp {
+            agent,
+            interface,
+            security,
+            timeout,
+        }
+    }
+}
+
+/// SNMP Source of the external ip
+///
+/// It walks the IP-MIB address table (`ipAddressIfIndex`, or `ipAdEntIfIndex` on agents without
+/// it) of a managed router for the addresses of the WAN interface, selected by ifIndex or by
+/// ifName. Link-local IPv6 addresses are skipped.
+///
+/// SNMPv2c and SNMPv3 (with HMAC-MD5, HMAC-SHA-1 or HMAC-SHA-256 authentication and AES-128
+/// privacy) are supported.
+///
+/// The default gateway is only discovered on Linux, elsewhere the agent must be set in the
+/// builder.
+///
+/// The feature "discover_snmp" must be enabled to use this (off by default)
+#[derive(Debug, Clone)]
+pub struct Snmp {
+    agent: Option<SocketAddr>,
+    interface: SnmpInterface,
+    security: Security,
+    timeout: Duration,
+}
+
+impl Snmp {
+    fn agent(&self) -> Result<SocketAddr, Error> {
+        match self.agent {
+            Some(agent) => Ok(agent),
+            None => {
+                gateway::default_gateway_address(false, SNMP_PORT).ok_or(Error::GatewayNotFound)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Snmp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.agent {
+            Some(agent) => write!(f, "SNMP: {}", agent),
+            None => write!(f, "SNMP"),
+        }
+    }
+}
+
+/// Returns the BER encoding of the value
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    if content.len() < 0x80 {
+        out.push(content.len() as u8);
+    } else {
+        let len = (content.len() as u32).to_be_bytes();
+        let skip = len.iter().take_while(|byte| **byte == 0).count();
+        out.push(0x80 | (len.len() - skip) as u8);
+        out.extend_from_slice(&len[skip..]);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn integer(value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let mut start = 0;
+    // Strips the leading bytes repeating the sign bit
+    while start < bytes.len() - 1
+        && ((bytes[start] == 0 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0))
+    {
+        start += 1;
+    }
+    tlv(INTEGER, &bytes[start..])
+}
+
+fn octet_string(value: &[u8]) -> Vec<u8> {
+    tlv(OCTET_STRING, value)
+}
+
+fn sequence(parts: &[Vec<u8>]) -> Vec<u8> {
+    tlv(SEQUENCE, &parts.concat())
+}
+
+fn object_identifier(oid: &[u32]) -> Vec<u8> {
+    let mut content = Vec::new();
+    let first = oid[0] * 40 + oid.get(1).copied().unwrap_or(0);
+    for component in std::iter::once(first).chain(oid.iter().skip(2).copied()) {
+        let mut bytes = vec![(component & 0x7f) as u8];
+        let mut rest = component >> 7;
+        while rest > 0 {
+            bytes.push(0x80 | (rest & 0x7f) as u8);
+            rest >>= 7;
+        }
+        content.extend(bytes.iter().rev());
+    }
+    tlv(OBJECT_IDENTIFIER, &content)
+}
+
+/// Reads the next BER value, returning its tag and content
+fn read_tlv<'a>(input: &mut &'a [u8]) -> Option<(u8, &'a [u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let (len, remaining) = rest.split_at(count);
+        rest = remaining;
+        len.iter().fold(0, |len, byte| (len << 8) | *byte as usize)
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let (content, rest) = rest.split_at(len);
+    *input = rest;
+    Some((tag, content))
+}
+
+/// Reads the next BER value, if it has the expected tag
+fn read<'a>(input: &mut &'a [u8], expected: u8) -> Option<&'a [u8]> {
+    let (tag, content) = read_tlv(input)?;
+    (tag == expected).then_some(content)
+}
+
+fn read_integer(input: &mut &[u8]) -> Option<i64> {
+    decode_integer(read(input, INTEGER)?)
+}
+
+fn decode_integer(content: &[u8]) -> Option<i64> {
+    if content.is_empty() || content.len() > 8 {
+        return None;
+    }
+    let sign = if content[0] & 0x80 != 0 { -1 } else { 0 };
+    Some(
+        content
+            .iter()
+            .fold(sign, |value, byte| (value << 8) | *byte as i64),
+    )
+}
+
+fn decode_object_identifier(content: &[u8]) -> Option<Vec<u32>> {
+    if content.last()? & 0x80 != 0 {
+        return None;
+    }
+    let mut oid = Vec::new();
+    let mut value: u32 = 0;
+    for byte in content {
+        value = value.checked_mul(128)? | (byte & 0x7f) as u32;
+        if byte & 0x80 == 0 {
+            if oid.is_empty() {
+                let first = (value / 40).min(2);
+                oid.push(first);
+                oid.push(value - first * 40);
+            } else {
+                oid.push(value);
+            }
+            value = 0;
+        }
+    }
+    Some(oid)
+}
+
+fn format_oid(oid: &[u32]) -> String {
+    oid.iter().map(u32::to_string).collect::<Vec<_>>().join(".")
+}
+
+/// Value of a variable binding
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Integer(i64),
+    OctetString(Vec<u8>),
+    EndOfMibView,
+    Other,
+}
+
+#[derive(Debug)]
+struct Pdu {
+    tag: u8,
+    request_id: i64,
+    error_status: i64,
+    varbinds: Vec<(Vec<u32>, Value)>,
+}
+
+/// Returns the PDU with the variable bindings, whose values are already encoded
+fn encode_pdu(
+    tag: u8,
+    request_id: i64,
+    error_status: i64,
+    error_index: i64,
+    varbinds: &[(&[u32], Vec<u8>)],
+) -> Vec<u8> {
+    let varbinds: Vec<_> = varbinds
+        .iter()
+        .map(|(oid, value)| sequence(&[object_identifier(oid), value.clone()]))
+        .collect();
+    let content = [
+        integer(request_id),
+        integer(error_status),
+        integer(error_index),
+        sequence(&varbinds),
+    ]
+    .concat();
+    tlv(tag, &content)
+}
+
+fn decode_pdu(mut input: &[u8]) -> Option<Pdu> {
+    let (tag, mut content) = read_tlv(&mut input)?;
+    let request_id = read_integer(&mut content)?;
+    let error_status = read_integer(&mut content)?;
+    read_integer(&mut content)?;
+    let mut list = read(&mut content, SEQUENCE)?;
+    let mut varbinds = Vec::new();
+    while !list.is_empty() {
+        let mut varbind = read(&mut list, SEQUENCE)?;
+        let oid = decode_object_identifier(read(&mut varbind, OBJECT_IDENTIFIER)?)?;
+        let value = match read_tlv(&mut varbind)? {
+            (INTEGER, content) => Value::Integer(decode_integer(content)?),
+            (OCTET_STRING, content) => Value::OctetString(content.to_vec()),
+            (END_OF_MIB_VIEW, _) => Value::EndOfMibView,
+            _ => Value::Other,
+        };
+        varbinds.push((oid, value));
+    }
+    Some(Pdu {
+        tag,
+        request_id,
+        error_status,
+        varbinds,
+    })
+}
+
+fn encode_v2c(community: &str, pdu: Vec<u8>) -> Vec<u8> {
+    sequence(&[integer(1), octet_string(community.as_bytes()), pdu])
+}
+
+/// Decodes a SNMPv2c message, returning its community and PDU
+fn decode_v2c(message: &[u8]) -> Option<(&[u8], Pdu)> {
+    let mut input = message;
+    let mut content = read(&mut input, SEQUENCE)?;
+    if read_integer(&mut content)? != 1 {
+        return None;
+    }
+    let community = read(&mut content, OCTET_STRING)?;
+    Some((community, decode_pdu(content)?))
+}
+
+/// Authoritative engine of the agent, with its clock
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Engine {
+    id: Vec<u8>,
+    boots: i64,
+    time: i64,
+}
+
+/// Keys of the user localized to the authoritative engine
+#[derive(Clone, Default)]
+struct Keys {
+    auth: Option<(SnmpAuthProtocol, Vec<u8>)>,
+    privacy: Option<Vec<u8>>,
+}
+
+impl Keys {
+    fn localize(user: &SnmpV3User, engine_id: &[u8]) -> Self {
+        match &user.auth {
+            Some((protocol, password)) => Keys {
+                auth: Some((*protocol, protocol.localize_key(password, engine_id))),
+                // The privacy key is derived with the hash of the authentication protocol
+                privacy: user
+                    .privacy
+                    .as_ref()
+                    .map(|(SnmpPrivProtocol::Aes128, password)| {
+                        protocol.localize_key(password, engine_id)
+                    }),
+            },
+            None => Keys::default(),
+        }
+    }
+}
+
+impl SnmpAuthProtocol {
+    /// Returns the key of the password localized to the engine (RFC 3414 appendix A.2)
+    fn localize_key(self, password: &str, engine_id: &[u8]) -> Vec<u8> {
+        match self {
+            SnmpAuthProtocol::Md5 => localize_key::<Md5>(password.as_bytes(), engine_id),
+            SnmpAuthProtocol::Sha1 => localize_key::<Sha1>(password.as_bytes(), engine_id),
+            SnmpAuthProtocol::Sha256 => localize_key::<Sha256>(password.as_bytes(), engine_id),
+        }
+    }
+
+    /// Length of the truncated HMAC in the authentication parameters
+    fn mac_len(self) -> usize {
+        match self {
+            SnmpAuthProtocol::Md5 | SnmpAuthProtocol::Sha1 => 12,
+            SnmpAuthProtocol::Sha256 => 24,
+        }
+    }
+
+    fn mac(self, key: &[u8], message: &[u8]) -> Vec<u8> {
+        let mac = match self {
+            SnmpAuthProtocol::Md5 => Hmac::<Md5>::new_from_slice(key)
+                .map(|mac| mac.chain_update(message).finalize().into_bytes().to_vec()),
+            SnmpAuthProtocol::Sha1 => Hmac::<Sha1>::new_from_slice(key)
+                .map(|mac| mac.chain_update(message).finalize().into_bytes().to_vec()),
+            SnmpAuthProtocol::Sha256 => Hmac::<Sha256>::new_from_slice(key)
+                .map(|mac| mac.chain_update(message).finalize().into_bytes().to_vec()),
+        }
+        .expect("HMAC accepts keys of any length");
+        mac[..self.mac_len()].to_vec()
+    }
+}
+
+/// Returns the key of the password, hashing it repeated over one megabyte (RFC 3414 appendix
+/// A.2.1)
+fn password_to_key<D: Digest>(password: &[u8]) -> Vec<u8> {
+    let mut hasher = D::new();
+    let mut repeated = password.iter().cycle();
+    let mut block = [0; 64];
+    if !password.is_empty() {
+        for _ in 0..(1 << 20) / block.len() {
+            block.fill_with(|| *repeated.next().unwrap());
+            hasher.update(block);
+        }
+    }
+    hasher.finalize().to_vec()
+}
+
+fn localize_key<D: Digest>(password: &[u8], engine_id: &[u8]) -> Vec<u8> {
+    let key = password_to_key::<D>(password);
+    let mut hasher = D::new();
+    hasher.update(&key);
+    hasher.update(engine_id);
+    hasher.update(&key);
+    hasher.finalize().to_vec()
+}
+
+/// Encrypts or decrypts the scoped PDU with AES-128-CFB (RFC 3826 section 3.1)
+fn aes_cfb(key: &[u8], engine: &Engine, salt: &[u8], mut data: Vec<u8>, encrypt: bool) -> Vec<u8> {
+    let mut iv = [0; 16];
+    iv[..4].copy_from_slice(&(engine.boots as u32).to_be_bytes());
+    iv[4..8].copy_from_slice(&(engine.time as u32).to_be_bytes());
+    iv[8..].copy_from_slice(salt);
+    if encrypt {
+        cfb_mode::Encryptor::<Aes128>::new(key[..16].into(), &iv.into()).encrypt(&mut data);
+    } else {
+        cfb_mode::Decryptor::<Aes128>::new(key[..16].into(), &iv.into()).decrypt(&mut data);
+    }
+    data
+}
+
+/// Returns the SNMPv3 message carrying the PDU, authenticated and encrypted as the keys allow
+fn encode_v3(
+    msg_id: i64,
+    username: &str,
+    engine: &Engine,
+    keys: &Keys,
+    reportable: bool,
+    pdu: Vec<u8>,
+    salt: u64,
+) -> Vec<u8> {
+    let mut flags = if reportable { FLAG_REPORTABLE } else { 0 };
+    let scoped = sequence(&[octet_string(&engine.id), octet_string(&[]), pdu]);
+    let (data, priv_params) = match &keys.privacy {
+        Some(key) => {
+            flags |= FLAG_PRIV;
+            let salt = salt.to_be_bytes();
+            let encrypted = aes_cfb(key, engine, &salt, scoped, true);
+            (octet_string(&encrypted), salt.to_vec())
+        }
+        None => (scoped, vec![]),
+    };
+    let mac_len = match keys.auth {
+        Some((protocol, _)) => {
+            flags |= FLAG_AUTH;
+            protocol.mac_len()
+        }
+        None => 0,
+    };
+    let security = sequence(&[
+        octet_string(&engine.id),
+        integer(engine.boots),
+        integer(engine.time),
+        octet_string(username.as_bytes()),
+        octet_string(&vec![0; mac_len]),
+        octet_string(&priv_params),
+    ]);
+    let global = sequence(&[
+        integer(msg_id),
+        integer(MAX_MESSAGE_SIZE as i64),
+        octet_string(&[flags]),
+        integer(USM),
+    ]);
+    // Only the privacy parameters and the data follow the authentication parameters
+    let after_mac = octet_string(&priv_params).len() + data.len();
+    let mut message = sequence(&[integer(3), global, octet_string(&security), data]);
+    if let Some((protocol, key)) = &keys.auth {
+        let end = message.len() - after_mac;
+        let mac = protocol.mac(key, &message);
+        message[end - mac_len..end].copy_from_slice(&mac);
+    }
+    message
+}
+
+/// SNMPv3 message as received
+struct V3Message<'a> {
+    raw: &'a [u8],
+    msg_id: i64,
+    flags: u8,
+    engine: Engine,
+    auth_params: &'a [u8],
+    priv_params: &'a [u8],
+    data: (u8, &'a [u8]),
+}
+
+fn decode_v3(message: &[u8]) -> Option<V3Message<'_>> {
+    let mut input = message;
+    let mut content = read(&mut input, SEQUENCE)?;
+    if read_integer(&mut content)? != 3 {
+        return None;
+    }
+    let mut global = read(&mut content, SEQUENCE)?;
+    let msg_id = read_integer(&mut global)?;
+    read_integer(&mut global)?;
+    let flags = *read(&mut global, OCTET_STRING)?.first()?;
+    if read_integer(&mut global)? != USM {
+        return None;
+    }
+    let mut security = read(&mut content, OCTET_STRING)?;
+    let mut usm = read(&mut security, SEQUENCE)?;
+    let engine = Engine {
+        id: read(&mut usm, OCTET_STRING)?.to_vec(),
+        boots: read_integer(&mut usm)?,
+        time: read_integer(&mut usm)?,
+    };
+    read(&mut usm, OCTET_STRING)?;
+    let auth_params = read(&mut usm, OCTET_STRING)?;
+    let priv_params = read(&mut usm, OCTET_STRING)?;
+    let data = read_tlv(&mut content)?;
+    Some(V3Message {
+        raw: message,
+        msg_id,
+        flags,
+        engine,
+        auth_params,
+        priv_params,
+        data,
+    })
+}
+
+impl V3Message<'_> {
+    /// Authenticates and decrypts the message with the keys, returning its PDU
+    ///
+    /// Unauthenticated messages are only accepted when they carry a report, as agents send
+    /// those when they can't authenticate the request.
+    fn open(&self, keys: &Keys) -> Result<Pdu, Error> {
+        if self.flags & FLAG_AUTH != 0 {
+            let (protocol, key) = keys.auth.as_ref().ok_or(Error::SnmpAuthentication)?;
+            if self.auth_params.len() != protocol.mac_len() {
+                return Err(Error::SnmpAuthentication);
+            }
+            let offset = self.auth_params.as_ptr() as usize - self.raw.as_ptr() as usize;
+            let mut message = self.raw.to_vec();
+            message[offset..offset + self.auth_params.len()].fill(0);
+            if protocol.mac(key, &message) != self.auth_params {
+                return Err(Error::SnmpAuthentication);
+            }
+        }
+        let scoped = match self.data {
+            (OCTET_STRING, encrypted) if self.flags & FLAG_PRIV != 0 => {
+                let key = match (&keys.privacy, self.flags & FLAG_AUTH != 0) {
+                    (Some(key), true) if self.priv_params.len() == 8 => key,
+                    _ => return Err(Error::SnmpAuthentication),
+                };
+                aes_cfb(
+                    key,
+                    &self.engine,
+                    self.priv_params,
+                    encrypted.to_vec(),
+                    false,
+                )
+            }
+            (SEQUENCE, content) if self.flags & FLAG_PRIV == 0 => content.to_vec(),
+            _ => return Err(Error::SnmpInvalidReply),
+        };
+        // The decrypted data is a full sequence, while the plain one was already unwrapped
+        let mut content = if self.flags & FLAG_PRIV != 0 {
+            let mut input = scoped.as_slice();
+            read(&mut input, SEQUENCE).ok_or(Error::SnmpInvalidReply)?
+        } else {
+            scoped.as_slice()
+        };
+        read(&mut content, OCTET_STRING).ok_or(Error::SnmpInvalidReply)?;
+        read(&mut content, OCTET_STRING).ok_or(Error::SnmpInvalidReply)?;
+        let pdu = decode_pdu(content).ok_or(Error::SnmpInvalidReply)?;
+        if self.flags & FLAG_AUTH == 0 && keys.auth.is_some() && pdu.tag != REPORT {
+            return Err(Error::SnmpAuthentication);
+        }
+        Ok(pdu)
+    }
+}
+
+/// Requests sent to the agent over the same socket
+struct Session<'a> {
+    snmp: &'a Snmp,
+    socket: UdpSocket,
+    next_id: i64,
+    next_salt: u64,
+    /// Engine and localized keys, once discovered (SNMPv3 only)
+    engine: Option<(Engine, Keys)>,
+}
+
+impl<'a> Session<'a> {
+    async fn connect(snmp: &'a Snmp, agent: SocketAddr) -> Result<Session<'a>, Error> {
+        let socket = gateway::connect(agent).await.map_err(Error::SnmpIo)?;
+        Ok(Session {
+            snmp,
+            socket,
+            next_id: rand::random_range(0..0x7fff_ffff),
+            next_salt: rand::random(),
+            engine: None,
+        })
+    }
+
+    fn next_id(&mut self) -> i64 {
+        self.next_id = (self.next_id + 1) & 0x7fff_ffff;
+        self.next_id
+    }
+
+    /// Sends the request until a reply is received, skipping the late replies to the previous
+    /// requests, whose ID differs from `id`
+    async fn exchange<T, F>(&self, request: &[u8], id: i64, decode: F) -> Result<T, Error>
+    where
+        F: Fn(&[u8]) -> Result<(i64, T), Error>,
+    {
+        let mut reply = gateway::exchange(
+            &self.socket,
+            request,
+            MAX_MESSAGE_SIZE,
+            INITIAL_INTERVAL,
+            self.snmp.timeout,
+        )
+        .await
+        .map_err(Error::SnmpIo)?;
+        loop {
+            let (reply_id, value) = decode(&reply)?;
+            if reply_id == id {
+                return Ok(value);
+            }
+            trace!("Skipping SNMP reply {} while waiting for {}", reply_id, id);
+            let mut buf = vec![0; MAX_MESSAGE_SIZE];
+            let len = tokio::time::timeout(self.snmp.timeout, self.socket.recv(&mut buf))
+                .await
+                .map_err(|_| Error::SnmpIo(std::io::ErrorKind::TimedOut.into()))?
+                .map_err(Error::SnmpIo)?;
+            buf.truncate(len);
+            reply = buf;
+        }
+    }
+
+    /// Asks the engine ID and clock of the agent, and localizes the keys of the user to it
+    /// (RFC 3414 section 4)
+    async fn discover(&mut self, user: &SnmpV3User) -> Result<(), Error> {
+        let msg_id = self.next_id();
+        let pdu = encode_pdu(GET_REQUEST, msg_id, 0, 0, &[]);
+        let request = encode_v3(
+            msg_id,
+            "",
+            &Engine::default(),
+            &Keys::default(),
+            true,
+            pdu,
+            0,
+        );
+        let engine = self
+            .exchange(&request, msg_id, |reply| {
+                let message = decode_v3(reply).ok_or(Error::SnmpInvalidReply)?;
+                Ok((message.msg_id, message.engine))
+            })
+            .await?;
+        if engine.id.is_empty() {
+            return Err(Error::SnmpInvalidReply);
+        }
+        trace!("Discovered SNMP engine {:?}", engine);
+        let keys = Keys::localize(user, &engine.id);
+        self.engine = Some((engine, keys));
+        Ok(())
+    }
+
+    /// Sends a GetBulk request for the variables following `oid`
+    async fn get_bulk(&mut self, oid: &[u32]) -> Result<Pdu, Error> {
+        let varbinds = [(oid, tlv(NULL, &[]))];
+        let user = match &self.snmp.security {
+            Security::Community(community) => {
+                let id = self.next_id();
+                let pdu = encode_pdu(GET_BULK_REQUEST, id, 0, MAX_REPETITIONS, &varbinds);
+                let request = encode_v2c(community, pdu);
+                return self
+                    .exchange(&request, id, |reply| {
+                        let (_, pdu) = decode_v2c(reply).ok_or(Error::SnmpInvalidReply)?;
+                        Ok((pdu.request_id, pdu))
+                    })
+                    .await;
+            }
+            Security::User(user) => user,
+        };
+        if self.engine.is_none() {
+            self.discover(user).await?;
+        }
+        let mut resynchronized = false;
+        loop {
+            let id = self.next_id();
+            let salt = self.next_salt;
+            self.next_salt = self.next_salt.wrapping_add(1);
+            let (engine, keys) = self.engine.as_ref().expect("engine discovered");
+            let pdu = encode_pdu(GET_BULK_REQUEST, id, 0, MAX_REPETITIONS, &varbinds);
+            let request = encode_v3(id, &user.username, engine, keys, true, pdu, salt);
+            let (engine, pdu) = self
+                .exchange(&request, id, |reply| {
+                    let message = decode_v3(reply).ok_or(Error::SnmpInvalidReply)?;
+                    let pdu = message.open(keys)?;
+                    Ok((message.msg_id, (message.engine, pdu)))
+                })
+                .await?;
+            if pdu.tag != REPORT {
+                return Ok(pdu);
+            }
+            let report = pdu.varbinds.first().map(|(oid, _)| oid.as_slice());
+            // The clock of the agent is only known after the first authenticated exchange
+            if report == Some(USM_STATS_NOT_IN_TIME_WINDOWS) && !resynchronized {
+                trace!("Resynchronizing with SNMP engine clock {:?}", engine);
+                if let Some((current, _)) = self.engine.as_mut() {
+                    current.boots = engine.boots;
+                    current.time = engine.time;
+                }
+                resynchronized = true;
+                continue;
+            }
+            return Err(Error::SnmpReport(
+                report.map(format_oid).unwrap_or_default(),
+            ));
+        }
+    }
+
+    /// Returns the variables under `root`
+    async fn walk(&mut self, root: &[u32]) -> Result<Vec<(Vec<u32>, Value)>, Error> {
+        let mut rows: Vec<(Vec<u32>, Value)> = Vec::new();
+        let mut oid = root.to_vec();
+        loop {
+            let pdu = self.get_bulk(&oid).await?;
+            if pdu.tag != RESPONSE {
+                return Err(Error::SnmpInvalidReply);
+            }
+            if pdu.error_status != 0 {
+                return Err(Error::SnmpErrorStatus(pdu.error_status));
+            }
+            if pdu.varbinds.is_empty() {
+                return Ok(rows);
+            }
+            for (name, value) in pdu.varbinds {
+                // Agents must return increasing OIDs, stopping otherwise avoids looping forever
+                if !name.starts_with(root) || value == Value::EndOfMibView || name <= oid {
+                    return Ok(rows);
+                }
+                oid.clone_from(&name);
+                rows.push((name, value));
+            }
+        }
+    }
+}
+
+/// Returns the addresses of the interface in the `ipAddressIfIndex` rows
+fn ip_address_addresses(rows: &[(Vec<u32>, Value)], if_index: i64) -> Vec<IpAddr> {
+    rows.iter()
+        .filter(|(_, value)| *value == Value::Integer(if_index))
+        .filter_map(|(oid, _)| {
+            // The index is the address type followed by the address as a length-prefixed string
+            let (address_type, address) = oid[IP_ADDRESS_IF_INDEX.len()..].split_first()?;
+            let (len, address) = address.split_first()?;
+            let address: Vec<u8> = address
+                .iter()
+                .map(|byte| u8::try_from(*byte).ok())
+                .collect::<Option<_>>()?;
+            match (address_type, len) {
+                (1, 4) => Some(IpAddr::from(<[u8; 4]>::try_from(address).ok()?)),
+                (2, 16) => Some(IpAddr::from(<[u8; 16]>::try_from(address).ok()?)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Returns the addresses of the interface in the `ipAdEntIfIndex` rows
+fn ip_ad_ent_addresses(rows: &[(Vec<u32>, Value)], if_index: i64) -> Vec<IpAddr> {
+    rows.iter()
+        .filter(|(_, value)| *value == Value::Integer(if_index))
+        .filter_map(|(oid, _)| match oid[IP_AD_ENT_IF_INDEX.len()..] {
+            [a, b, c, d] => Some(IpAddr::V4(Ipv4Addr::new(
+                u8::try_from(a).ok()?,
+                u8::try_from(b).ok()?,
+                u8::try_from(c).ok()?,
+                u8::try_from(d).ok()?,
+            ))),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns the address of the family, preferring IPv4 for any family
+fn select(addresses: &[IpAddr], family: Family) -> Option<IpAddr> {
+    addresses
+        .iter()
+        .filter(|ip| match ip {
+            IpAddr::V4(_) => matches!(family, Family::IPv4 | Family::Any),
+            IpAddr::V6(ip) => {
+                matches!(family, Family::IPv6 | Family::Any)
+                    && !ip.is_unicast_link_local()
+                    && *ip != Ipv6Addr::LOCALHOST
+            }
+        })
+        .min_by_key(|ip| ip.is_ipv6())
+        .copied()
+}
+
+impl Source for Snmp {
+    fn get_ip(&self, family: Family) -> IpFuture<'_> {
+        async fn run(_self: &Snmp, family: Family) -> IpResult {
+            let agent = _self.agent()?;
+            trace!("Querying SNMP agent {}", agent);
+            let mut session = Session::connect(_self, agent).await?;
+            let if_index = match &_self.interface {
+                SnmpInterface::Index(index) => *index as i64,
+                SnmpInterface::Name(name) => session
+                    .walk(IF_NAME)
+                    .await?
+                    .into_iter()
+                    .find(|(_, value)| *value == Value::OctetString(name.as_bytes().to_vec()))
+                    .and_then(|(oid, _)| oid.last().copied())
+                    .ok_or(Error::SnmpInterfaceNotFound)?
+                    as i64,
+            };
+            let rows = session.walk(IP_ADDRESS_IF_INDEX).await?;
+            let mut addresses = ip_address_addresses(&rows, if_index);
+            if select(&addresses, family).is_none() && family != Family::IPv6 {
+                trace!("No address in ipAddressTable, falling back to ipAddrTable");
+                let rows = session.walk(IP_AD_ENT_IF_INDEX).await?;
+                addresses.extend(ip_ad_ent_addresses(&rows, if_index));
+            }
+            select(&addresses, family).ok_or(Error::SnmpAddressNotFound)
+        }
+        Box::pin(run(self, family))
+    }
+
+    fn box_clone(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::test_util::serve_udp;
+    use std::collections::BTreeMap;
+    use std::ops::Bound;
+    use tokio_test::block_on;
+
+    /// Rows of the stand-in agent, with their encoded value
+    type Table = BTreeMap<Vec<u32>, Vec<u8>>;
+
+    /// Rows in every GetBulk reply of the stand-in agent, so that walks take several requests
+    const PAGE: usize = 3;
+
+    const ENGINE_ID: &[u8] = b"\x80\x00\x1f\x88\x04standin";
+    const USM_STATS_UNKNOWN_ENGINE_IDS: &[u32] = &[1, 3, 6, 1, 6, 3, 15, 1, 1, 4, 0];
+    const USM_STATS_WRONG_DIGESTS: &[u32] = &[1, 3, 6, 1, 6, 3, 15, 1, 1, 5, 0];
+
+    fn table(ip_address_table: bool) -> Table {
+        let mut table = Table::new();
+        let mut row = |oid: &[u32], index: &[u32], value: Vec<u8>| {
+            table.insert([oid, index].concat(), value);
+        };
+        row(IF_NAME, &[1], octet_string(b"lo"));
+        row(IF_NAME, &[7], octet_string(b"pppoe-wan"));
+        if ip_address_table {
+            let link_local: [u32; 16] = [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+            let global: [u32; 16] = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7];
+            row(IP_ADDRESS_IF_INDEX, &[1, 4, 127, 0, 0, 1], integer(1));
+            row(IP_ADDRESS_IF_INDEX, &[1, 4, 203, 0, 113, 7], integer(7));
+            row(
+                IP_ADDRESS_IF_INDEX,
+                &[&[2, 16], &link_local[..]].concat(),
+                integer(7),
+            );
+            row(
+                IP_ADDRESS_IF_INDEX,
+                &[&[2, 16], &global[..]].concat(),
+                integer(7),
+            );
+        } else {
+            row(IP_AD_ENT_IF_INDEX, &[127, 0, 0, 1], integer(1));
+            row(IP_AD_ENT_IF_INDEX, &[203, 0, 113, 9], integer(7));
+        }
+        table
+    }
+
+    fn get_bulk(table: &Table, pdu: &Pdu) -> Vec<u8> {
+        let start = &pdu.varbinds[0].0;
+        let mut varbinds: Vec<(&[u32], Vec<u8>)> = table
+            .range::<Vec<u32>, _>((Bound::Excluded(start), Bound::Unbounded))
+            .take(PAGE)
+            .map(|(oid, value)| (oid.as_slice(), value.clone()))
+            .collect();
+        if varbinds.is_empty() {
+            varbinds.push((start, tlv(END_OF_MIB_VIEW, &[])));
+        }
+        encode_pdu(RESPONSE, pdu.request_id, 0, 0, &varbinds)
+    }
+
+    fn reply_v2c(table: &Table, request: &[u8]) -> Option<Vec<u8>> {
+        let (community, pdu) = decode_v2c(request)?;
+        // Like real agents, requests with another community are dropped
+        (community == b"public").then(|| encode_v2c("public", get_bulk(table, &pdu)))
+    }
+
+    fn reply_v3(table: &Table, user: &SnmpV3User, keys: &Keys, request: &[u8]) -> Option<Vec<u8>> {
+        let engine = Engine {
+            id: ENGINE_ID.to_vec(),
+            boots: 3,
+            time: 1200,
+        };
+        let message = decode_v3(request)?;
+        if message.engine.id.is_empty() {
+            // The clock is left out of the discovery report, to make the client resynchronize
+            let pdu = message.open(&Keys::default()).ok()?;
+            let varbinds = [(USM_STATS_UNKNOWN_ENGINE_IDS, tlv(0x41, &[1]))];
+            let report = encode_pdu(REPORT, pdu.request_id, 0, 0, &varbinds);
+            let discovered = Engine {
+                id: ENGINE_ID.to_vec(),
+                ..Engine::default()
+            };
+            let keys = Keys::default();
+            return Some(encode_v3(
+                message.msg_id,
+                "",
+                &discovered,
+                &keys,
+                false,
+                report,
+                0,
+            ));
+        }
+        let (pdu, report, keys) = match message.open(keys) {
+            Ok(pdu) if message.engine == engine => (get_bulk(table, &pdu), false, keys.clone()),
+            Ok(pdu) => {
+                let varbinds = [(USM_STATS_NOT_IN_TIME_WINDOWS, tlv(0x41, &[1]))];
+                let report = encode_pdu(REPORT, pdu.request_id, 0, 0, &varbinds);
+                let keys = Keys {
+                    auth: keys.auth.clone(),
+                    privacy: None,
+                };
+                (report, true, keys)
+            }
+            Err(_) => {
+                let varbinds = [(USM_STATS_WRONG_DIGESTS, tlv(0x41, &[1]))];
+                let report = encode_pdu(REPORT, message.msg_id, 0, 0, &varbinds);
+                (report, true, Keys::default())
+            }
+        };
+        let username = if report { "" } else { &user.username };
+        Some(encode_v3(
+            message.msg_id,
+            username,
+            &engine,
+            &keys,
+            false,
+            pdu,
+            42,
+        ))
+    }
+
+    /// Runs a stand-in agent on a loopback port serving the table, to SNMPv2c requests of the
+    /// `public` community or to SNMPv3 requests of `user`
+    fn serve(table: Table, user: Option<SnmpV3User>) -> SocketAddr {
+        let keys = user
+            .as_ref()
+            .map(|user| Keys::localize(user, ENGINE_ID))
+            .unwrap_or_default();
+        serve_udp(
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            move |request| match &user {
+                Some(user) => reply_v3(&table, user, &keys, request),
+                None => reply_v2c(&table, request),
+            },
+        )
+    }
+
+    fn source(agent: SocketAddr, interface: SnmpInterface) -> SnmpBuilder {
+        SnmpBuilder::new(interface)
+            .with_agent(agent)
+            .with_timeout(Duration::from_millis(500))
+    }
+
+    #[test]
+    fn test_localize_key() {
+        // RFC 3414 appendix A.3
+        assert_eq!(
+            password_to_key::<Md5>(b"maplesyrup"),
+            [
+                0x9f, 0xaf, 0x32, 0x83, 0x88, 0x4e, 0x92, 0x83, 0x4e, 0xbc, 0x98, 0x47, 0xd8, 0xed,
+                0xd9, 0x63
+            ]
+        );
+        assert_eq!(
+            password_to_key::<Sha1>(b"maplesyrup"),
+            [
+                0x9f, 0xb5, 0xcc, 0x03, 0x81, 0x49, 0x7b, 0x37, 0x93, 0x52, 0x89, 0x39, 0xff, 0x78,
+                0x8d, 0x5d, 0x79, 0x14, 0x52, 0x11
+            ]
+        );
+        let engine_id = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+        let key = SnmpAuthProtocol::Md5.localize_key("maplesyrup", &engine_id);
+        assert_eq!(
+            key,
+            [
+                0x52, 0x6f, 0x5e, 0xed, 0x9f, 0xcc, 0xe2, 0x6f, 0x89, 0x64, 0xc2, 0x93, 0x07, 0x87,
+                0xd8, 0x2b
+            ]
+        );
+        let key = SnmpAuthProtocol::Sha1.localize_key("maplesyrup", &engine_id);
+        assert_eq!(
+            key,
+            [
+                0x66, 0x95, 0xfe, 0xbc, 0x92, 0x88, 0xe3, 0x62, 0x82, 0x23, 0x5f, 0xc7, 0x15, 0x1f,
+                0x12, 0x84, 0x97, 0xb3, 0x8f, 0x3f
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mac() {
+        // RFC 2202 and RFC 4231 test case 1, truncated to 96 and 192 bits (RFC 3414 sections
+        // 6.3.1 and 7.3.1, RFC 7860 section 4.2.1)
+        let key = [0x0b; 20];
+        assert_eq!(
+            SnmpAuthProtocol::Md5.mac(&key[..16], b"Hi There"),
+            [
+                0x92, 0x94, 0x72, 0x7a, 0x36, 0x38, 0xbb, 0x1c, 0x13, 0xf4, 0x8e, 0xf8
+            ]
+        );
+        assert_eq!(
+            SnmpAuthProtocol::Sha1.mac(&key, b"Hi There"),
+            [
+                0xb6, 0x17, 0x31, 0x86, 0x55, 0x05, 0x72, 0x64, 0xe2, 0x8b, 0xc0, 0xb6
+            ]
+        );
+        assert_eq!(
+            SnmpAuthProtocol::Sha256.mac(&key, b"Hi There"),
+            [
+                0xb0, 0x34, 0x4c, 0x61, 0xd8, 0xdb, 0x38, 0x53, 0x5c, 0xa8, 0xaf, 0xce, 0xaf, 0x0b,
+                0xf1, 0x2b, 0x88, 0x1d, 0xc2, 0x00, 0xc9, 0x83, 0x3d, 0xa7
+            ]
+        );
+    }
+
+    #[test]
+    fn test_aes_cfb() {
+        // NIST SP 800-38A F.3.13, with the IV built from the engine boots and time and the salt
+        // (RFC 3826 section 3.1.2.1)
+        let key = [
+            0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf,
+            0x4f, 0x3c,
+        ];
+        let engine = Engine {
+            id: Vec::new(),
+            boots: 0x0001_0203,
+            time: 0x0405_0607,
+        };
+        let salt = [0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f];
+        let plaintext = vec![
+            0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93,
+            0x17, 0x2a,
+        ];
+        let ciphertext = aes_cfb(&key, &engine, &salt, plaintext.clone(), true);
+        assert_eq!(
+            ciphertext,
+            [
+                0x3b, 0x3f, 0xd9, 0x2e, 0xb7, 0x2d, 0xad, 0x20, 0x33, 0x34, 0x49, 0xf8, 0xe8, 0x3c,
+                0xfb, 0x4a
+            ]
+        );
+        assert_eq!(aes_cfb(&key, &engine, &salt, ciphertext, false), plaintext);
+    }
+
+    #[test]
+    fn test_ber() {
+        assert_eq!(integer(0), [INTEGER, 1, 0]);
+        assert_eq!(integer(128), [INTEGER, 2, 0, 0x80]);
+        assert_eq!(integer(-1), [INTEGER, 1, 0xff]);
+        let mut input = &integer(-129)[..];
+        assert_eq!(read_integer(&mut input), Some(-129));
+
+        let oid = [1, 3, 6, 1, 4, 1, 16384, 127, 128];
+        let encoded = object_identifier(&oid);
+        let mut input = &encoded[..];
+        let content = read(&mut input, OBJECT_IDENTIFIER).expect("object identifier");
+        assert_eq!(decode_object_identifier(content), Some(oid.to_vec()));
+
+        let long = octet_string(&[0; 300]);
+        assert_eq!(long[..4], [OCTET_STRING, 0x82, 0x01, 0x2c]);
+        let mut input = &long[..];
+        assert_eq!(read(&mut input, OCTET_STRING), Some(&[0; 300][..]));
+        assert_eq!(read(&mut &long[..10], OCTET_STRING), None);
+    }
+
+    #[test]
+    fn test_v2c_interface_name() {
+        let agent = serve(table(true), None);
+        let snmp = source(agent, SnmpInterface::Name(String::from("pppoe-wan"))).build();
+        let ip = block_on(snmp.get_ip(Family::Any)).expect("address");
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7)));
+        let ip = block_on(snmp.get_ip(Family::IPv6)).expect("address");
+        assert_eq!(ip, "2001:db8::7".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_v2c_interface_index() {
+        let agent = serve(table(true), None);
+        let snmp = source(agent, SnmpInterface::Index(1)).build();
+        let ip = block_on(snmp.get_ip(Family::IPv4)).expect("address");
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert!(matches!(
+            block_on(snmp.get_ip(Family::IPv6)),
+            Err(Error::SnmpAddressNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_v2c_interface_not_found() {
+        let agent = serve(table(true), None);
+        let snmp = source(agent, SnmpInterface::Name(String::from("eth9"))).build();
+        assert!(matches!(
+            block_on(snmp.get_ip(Family::Any)),
+            Err(Error::SnmpInterfaceNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_v2c_wrong_community() {
+        let agent = serve(table(true), None);
+        let snmp = source(agent, SnmpInterface::Index(7))
+            .with_community("private")
+            .build();
+        assert!(matches!(
+            block_on(snmp.get_ip(Family::Any)),
+            Err(Error::SnmpIo(_))
+        ));
+    }
+
+    #[test]
+    fn test_ip_addr_table_fallback() {
+        let agent = serve(table(false), None);
+        let snmp = source(agent, SnmpInterface::Name(String::from("pppoe-wan"))).build();
+        let ip = block_on(snmp.get_ip(Family::Any)).expect("address");
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 9)));
+    }
+
+    #[test]
+    fn test_v3() {
+        let users = [
+            SnmpV3User::new("monitor"),
+            SnmpV3User::with_auth("monitor", SnmpAuthProtocol::Sha1, "maplesyrup"),
+            SnmpV3User::with_auth_priv(
+                "monitor",
+                SnmpAuthProtocol::Sha256,
+                "maplesyrup",
+                SnmpPrivProtocol::Aes128,
+                "pancakes",
+            ),
+        ];
+        for user in users {
+            let agent = serve(table(true), Some(user.clone()));
+            let snmp = source(agent, SnmpInterface::Name(String::from("pppoe-wan")))
+                .with_v3_user(user)
+                .build();
+            let ip = block_on(snmp.get_ip(Family::Any)).expect("address");
+            assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7)));
+        }
+    }
+
+    #[test]
+    fn test_v3_wrong_password() {
+        let user = SnmpV3User::with_auth("monitor", SnmpAuthProtocol::Md5, "maplesyrup");
+        let agent = serve(table(true), Some(user));
+        let snmp = source(agent, SnmpInterface::Index(7))
+            .with_v3_user(SnmpV3User::with_auth(
+                "monitor",
+                SnmpAuthProtocol::Md5,
+                "pancakes",
+            ))
+            .build();
+        match block_on(snmp.get_ip(Family::Any)) {
+            Err(Error::SnmpReport(oid)) => assert_eq!(oid, format_oid(USM_STATS_WRONG_DIGESTS)),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Loopback stand-ins for the servers the sources are tested against
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, UdpSocket};
+
+/// HTTP request received by a stand-in server
+pub(crate) struct Request {
+    /// Request line and headers
+    pub(crate) head: String,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Request {
+    /// Returns the value of the header, whatever the case of its name
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.head.lines().skip(1).find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+}
+
+/// Reads an HTTP request, with the body of the length given by `Content-Length`, or returns
+/// `None` if the connection ends before
+pub(crate) fn read_request<S: Read>(stream: &mut S) -> Option<Request> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    let header_end = loop {
+        if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => request.extend_from_slice(&buf[..read]),
+        }
+    };
+    let mut request = Request {
+        head: String::from_utf8_lossy(&request[..header_end]).to_string(),
+        body: request[header_end..].to_vec(),
+    };
+    let length: usize = match request.header("content-length") {
+        Some(length) => length.parse().ok()?,
+        None => 0,
+    };
+    while request.body.len() < length {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => request.body.extend_from_slice(&buf[..read]),
+        }
+    }
+    Some(request)
+}
+
+/// Returns an HTTP response closing the connection, with the given headers (each ending with
+/// `\r\n`) and body
+pub(crate) fn response(status: &str, headers: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        headers,
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+/// Runs a stand-in HTTP server on a loopback port, answering every request with the response
+/// returned by `handle`, and returns its address
+pub(crate) fn serve_http<F>(mut handle: F) -> SocketAddr
+where
+    F: FnMut(Request) -> Vec<u8> + Send + 'static,
+{
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).expect("bind");
+    let address = listener.local_addr().expect("local address");
+    std::thread::spawn(move || {
+        while let Ok((mut stream, _)) = listener.accept() {
+            let Some(request) = read_request(&mut stream) else {
+                continue;
+            };
+            let _ = stream.write_all(&handle(request));
+        }
+    });
+    address
+}
+
+/// Runs a stand-in UDP server on a loopback port of the family of `ip`, answering every datagram
+/// with the one returned by `handle`, if any, and returns its address
+pub(crate) fn serve_udp<F>(ip: IpAddr, mut handle: F) -> SocketAddr
+where
+    F: FnMut(&[u8]) -> Option<Vec<u8>> + Send + 'static,
+{
+    let socket = UdpSocket::bind((ip, 0)).expect("bind");
+    let address = socket.local_addr().expect("local address");
+    std::thread::spawn(move || {
+        let mut buf = [0; 65535];
+        while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+            if let Some(reply) = handle(&buf[..len]) {
+                let _ = socket.send_to(&reply, peer);
+            }
+        }
+    });
+    address
+}
@@ -0,0 +1,396 @@
+use crate::sources::http::read_body;
+use crate::sources::interfaces::{Error, Family, IpFuture, IpResult, Source};
+use log::trace;
+use md5::{Digest, Md5};
+use std::time::Duration;
+
+/// Control URL of the WANIPConnection service on a Fritz!Box
+const FRITZBOX_CONTROL_URL: &str = "http://fritz.box:49000/upnp/control/wanipconnection1";
+
+/// TR-064 WANIPConnection service type
+const SERVICE: &str = "urn:dslforum-org:service:WANIPConnection:1";
+
+/// Largest SOAP reply accepted
+const MAX_REPLY_SIZE: usize = 64 * 1024;
+
+pub struct Tr064Builder {
+    control_url: String,
+    credentials: Option<(String, String)>,
+    timeout: Duration,
+}
+
+impl Tr064Builder {
+    pub fn new() -> Self {
+        Self {
+            control_url: String::from(FRITZBOX_CONTROL_URL),
+            credentials: None,
+            timeout: Duration::from_secs(5),
+        }
+    }
+    /// Sets the control URL of the WANIPConnection service, the one of a Fritz!Box otherwise
+    pub fn with_control_url<S: Into<String>>(mut self, control_url: S) -> Self {
+        self.control_url = control_url.into();
+        self
+    }
+    /// Authenticates with HTTP digest authentication when the device requires it
+    pub fn with_credentials<U: Into<String>, P: Into<String>>(
+        mut self,
+        username: U,
+        password: P,
+    ) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    pub fn build(self) -> Tr064 {
+        let Self {
+            control_url,
+            credentials,
+            timeout,
+        } = self;
+        Tr064 {
+            control_url,
+            credentials,
+            timeout,
+        }
+    }
+}
+
+impl Default for Tr064Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// TR-064 Source of the external ip
+///
+/// It asks a TR-064 device (e.g. an AVM Fritz!Box) for its WAN address over the local SOAP
+/// interface, with `GetExternalIPAddress` for IPv4 and `X_AVM_DE_GetExternalIPv6Address` for IPv6.
+///
+/// The feature "discover_tr064" must be enabled to use this (off by default)
+#[derive(Clone)]
+pub struct Tr064 {
+    control_url: String,
+    credentials: Option<(String, String)>,
+    timeout: Duration,
+}
+
+impl std::fmt::Debug for Tr064 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tr064")
+            .field("control_url", &self.control_url)
+            .field(
+                "username",
+                &self.credentials.as_ref().map(|(username, _)| username),
+            )
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl std::fmt::Display for Tr064 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TR-064: {}", self.control_url)
+    }
+}
+
+impl Tr064 {
+    /// Returns a source asking the Fritz!Box at `fritz.box`, which only resolves on AVM networks,
+    /// so it's not part of the default sources
+    pub fn source() -> Box<dyn Source> {
+        Box::new(Tr064Builder::new().build())
+    }
+
+    /// Calls the action of the WANIPConnection service, returning the reply body
+    async fn call(&self, action: &str) -> Result<String, Error> {
+        let client = reqwest::Client::builder().timeout(self.timeout).build()?;
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+            <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+            s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+            <s:Body><u:{action} xmlns:u=\"{SERVICE}\"></u:{action}></s:Body></s:Envelope>"
+        );
+        let request = |authorization: Option<String>| {
+            let request = client
+                .post(&self.control_url)
+                .header(reqwest::header::CONTENT_TYPE, "text/xml; charset=\"utf-8\"")
+                .header("SOAPAction", format!("\"{}#{}\"", SERVICE, action))
+                .body(body.clone());
+            match authorization {
+                Some(authorization) => {
+                    request.header(reqwest::header::AUTHORIZATION, authorization)
+                }
+                None => request,
+            }
+        };
+
+        trace!("Calling {} on {}", action, self.control_url);
+        let mut resp = request(None).send().await?;
+        if resp.status() == reqwest::StatusCode::UNAUTHORIZED {
+            let challenge = resp
+                .headers()
+                .get(reqwest::header::WWW_AUTHENTICATE)
+                .and_then(|value| value.to_str().ok());
+            let authorization = match (&self.credentials, challenge) {
+                (Some((username, password)), Some(challenge)) => {
+                    digest_authorization(challenge, username, password, "POST", resp.url().path())
+                }
+                _ => None,
+            };
+            if let Some(authorization) = authorization {
+                resp = request(Some(authorization)).send().await?;
+            }
+        }
+        if !resp.status().is_success() {
+            return Err(Error::HttpStatus(resp.status()));
+        }
+        let body = read_body(resp, MAX_REPLY_SIZE).await?;
+        Ok(std::str::from_utf8(&body)?.to_string())
+    }
+}
+
+/// Returns the text of the first element with the given name
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = xml[start..].find(&format!("</{}>", name))? + start;
+    Some(xml[start..end].trim())
+}
+
+fn md5_hex(data: &str) -> String {
+    Md5::digest(data.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Parses the parameters of a digest challenge (RFC 7616)
+fn challenge_params(challenge: &str) -> Option<Vec<(String, String)>> {
+    let mut rest = challenge.trim().strip_prefix("Digest")?.trim_start();
+    let mut params = Vec::new();
+    while !rest.is_empty() {
+        let (key, value) = rest.split_once('=')?;
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim_start();
+        let (value, remaining) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end], &quoted[end + 1..])
+            }
+            None => value.split_at(value.find(',').unwrap_or(value.len())),
+        };
+        params.push((key, value.trim().to_string()));
+        rest = remaining.trim_start().trim_start_matches(',').trim_start();
+    }
+    Some(params)
+}
+
+/// Computes the digest response (RFC 2617 section 3.2.2)
+fn digest_response(
+    username: &str,
+    password: &str,
+    realm: &str,
+    nonce: &str,
+    method: &str,
+    uri: &str,
+    qop: Option<(&str, &str)>,
+) -> String {
+    let ha1 = md5_hex(&format!("{}:{}:{}", username, realm, password));
+    let ha2 = md5_hex(&format!("{}:{}", method, uri));
+    match qop {
+        Some((nc, cnonce)) => md5_hex(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2)),
+        None => md5_hex(&format!("{}:{}:{}", ha1, nonce, ha2)),
+    }
+}
+
+/// Returns the Authorization header answering the digest challenge
+fn digest_authorization(
+    challenge: &str,
+    username: &str,
+    password: &str,
+    method: &str,
+    uri: &str,
+) -> Option<String> {
+    let params = challenge_params(challenge)?;
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+    let realm = param("realm")?;
+    let nonce = param("nonce")?;
+    if param("algorithm").is_some_and(|algorithm| !algorithm.eq_ignore_ascii_case("MD5")) {
+        return None;
+    }
+    let qop = param("qop").is_some_and(|qop| qop.split(',').any(|qop| qop.trim() == "auth"));
+    let cnonce = format!("{:016x}", rand::random::<u64>());
+    let nc = "00000001";
+
+    let response = digest_response(
+        username,
+        password,
+        realm,
+        nonce,
+        method,
+        uri,
+        qop.then_some((nc, cnonce.as_str())),
+    );
+    let mut authorization = format!(
+        "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm=MD5, response=\"{}\"",
+        username, realm, nonce, uri, response
+    );
+    if qop {
+        authorization.push_str(&format!(", qop=auth, nc={}, cnonce=\"{}\"", nc, cnonce));
+    }
+    if let Some(opaque) = param("opaque") {
+        authorization.push_str(&format!(", opaque=\"{}\"", opaque));
+    }
+    Some(authorization)
+}
+
+impl Source for Tr064 {
+    fn get_ip(&self, family: Family) -> IpFuture<'_> {
+        async fn run(_self: &Tr064, family: Family) -> IpResult {
+            let (action, name) = match family {
+                Family::IPv4 | Family::Any => ("GetExternalIPAddress", "NewExternalIPAddress"),
+                Family::IPv6 => ("X_AVM_DE_GetExternalIPv6Address", "NewExternalIPv6Address"),
+            };
+            let reply = _self.call(action).await?;
+            let address = element(&reply, name).ok_or(Error::Tr064InvalidReply)?;
+            if address.is_empty() {
+                // The device is not connected
+                return Err(Error::Tr064InvalidReply);
+            }
+            Ok(address.parse()?)
+        }
+        Box::pin(run(self, family))
+    }
+
+    fn box_clone(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::sources::test_util::{response, serve_http};
+    use std::net::IpAddr;
+    use tokio_test::block_on;
+
+    /// Runs a stand-in TR-064 device on a loopback port requiring digest authentication for
+    /// `user` and `secret`, and returns its control URL
+    fn serve(reply: &'static str) -> String {
+        let addr = serve_http(move |request| {
+            let authorized = request
+                .header("authorization")
+                .is_some_and(|authorization| {
+                    let params = challenge_params(authorization).expect("valid authorization");
+                    let param = |name: &str| {
+                        params
+                            .iter()
+                            .find(|(key, _)| key == name)
+                            .map(|(_, value)| value.as_str())
+                            .expect("parameter")
+                    };
+                    let expected = digest_response(
+                        "user",
+                        "secret",
+                        "F!Box SOAP-Auth",
+                        "c0ffee",
+                        "POST",
+                        "/upnp/control/wanipconnection1",
+                        Some((param("nc"), param("cnonce"))),
+                    );
+                    param("uri") == "/upnp/control/wanipconnection1"
+                        && param("response") == expected
+                });
+            if authorized {
+                response("200 OK", "Content-Type: text/xml\r\n", reply.as_bytes())
+            } else {
+                response(
+                    "401 Unauthorized",
+                    "WWW-Authenticate: Digest realm=\"F!Box SOAP-Auth\", nonce=\"c0ffee\", \
+                    algorithm=MD5, qop=\"auth\"\r\n",
+                    b"",
+                )
+            }
+        });
+        format!("http://{}/upnp/control/wanipconnection1", addr)
+    }
+
+    #[test]
+    fn test_digest_response() {
+        // RFC 2617 section 3.5
+        let response = digest_response(
+            "Mufasa",
+            "Circle Of Life",
+            "testrealm@host.com",
+            "dcd98b7102dd2f0e8b11d0f600bfb0c093",
+            "GET",
+            "/dir/index.html",
+            Some(("00000001", "0a4f113b")),
+        );
+        assert_eq!(response, "6629fae49393a05397450978507c4ef1");
+    }
+
+    #[test]
+    fn test_ipv6_with_credentials() {
+        let source = Tr064Builder::new()
+            .with_control_url(serve(
+                "<s:Envelope><s:Body><u:X_AVM_DE_GetExternalIPv6AddressResponse>\
+                <NewExternalIPv6Address>2001:db8::1</NewExternalIPv6Address>\
+                <NewPrefixLength>64</NewPrefixLength>\
+                </u:X_AVM_DE_GetExternalIPv6AddressResponse></s:Body></s:Envelope>",
+            ))
+            .with_credentials("user", "secret")
+            .build();
+        let ip = block_on(source.get_ip(Family::IPv6)).expect("valid reply");
+        assert_eq!(ip, "2001:db8::1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_wrong_credentials() {
+        let source = Tr064Builder::new()
+            .with_control_url(serve("<s:Envelope></s:Envelope>"))
+            .with_credentials("user", "wrong")
+            .build();
+        assert!(matches!(
+            block_on(source.get_ip(Family::IPv4)),
+            Err(Error::HttpStatus(reqwest::StatusCode::UNAUTHORIZED))
+        ));
+    }
+
+    #[test]
+    fn test_not_connected() {
+        let source = Tr064Builder::new()
+            .with_control_url(serve(
+                "<s:Envelope><s:Body><u:GetExternalIPAddressResponse>\
+                <NewExternalIPAddress></NewExternalIPAddress>\
+                </u:GetExternalIPAddressResponse></s:Body></s:Envelope>",
+            ))
+            .with_credentials("user", "secret")
+            .build();
+        assert!(matches!(
+            block_on(source.get_ip(Family::Any)),
+            Err(Error::Tr064InvalidReply)
+        ));
+    }
+
+    #[test]
+    fn test_reply_too_large() {
+        let reply = format!("<s:Envelope>{}</s:Envelope>", " ".repeat(MAX_REPLY_SIZE));
+        let source = Tr064Builder::new()
+            .with_control_url(serve(reply.leak()))
+            .with_credentials("user", "secret")
+            .build();
+        assert!(matches!(
+            block_on(source.get_ip(Family::Any)),
+            Err(Error::HttpBodyTooLarge { .. })
+        ));
+    }
+}
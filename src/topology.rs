@@ -0,0 +1,160 @@
+use crate::consensus::Consensus;
+
+use log::debug;
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+
+/// NAT topology between the host and the internet
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NatTopology {
+    /// The public address belongs to the host
+    Direct,
+    /// The public address belongs to the local gateway
+    SingleNat,
+    /// The local gateway is behind another NAT, its WAN address is a private address
+    DoubleNat,
+    /// The local gateway is behind a carrier-grade NAT, its WAN address is in the shared address
+    /// space (100.64.0.0/10, RFC 6598)
+    CarrierGradeNat,
+}
+
+/// Returns true if the address is in the shared address space of carrier-grade NATs
+fn is_shared(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64,
+        IpAddr::V6(_) => false,
+    }
+}
+
+/// Returns true if the address is private (RFC 1918) or unique local (RFC 4193)
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private(),
+        IpAddr::V6(ip) => ip.is_unique_local(),
+    }
+}
+
+/// Returns the local address used to reach the given address
+fn local_address(ip: IpAddr) -> Option<IpAddr> {
+    let bind: IpAddr = match ip {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => std::net::Ipv6Addr::UNSPECIFIED.into(),
+    };
+    // Connecting a UDP socket only selects the route, nothing is sent
+    let socket = UdpSocket::bind((bind, 0)).ok()?;
+    socket.connect((ip, 9)).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+impl NatTopology {
+    /// Classifies the topology from the local address used to reach the internet, the WAN
+    /// address reported by the local gateway and the public address.
+    ///
+    /// Returns None if the host is behind a NAT but the gateway address is unknown, or if the
+    /// gateway reports another public address (e.g. with several WAN links, or when the traffic
+    /// leaves through a VPN).
+    pub fn classify(
+        local: Option<IpAddr>,
+        gateway: Option<IpAddr>,
+        public: IpAddr,
+    ) -> Option<NatTopology> {
+        if local == Some(public) {
+            return Some(NatTopology::Direct);
+        }
+        match gateway? {
+            gateway if gateway == public => Some(NatTopology::SingleNat),
+            gateway if is_shared(gateway) => Some(NatTopology::CarrierGradeNat),
+            gateway if is_private(gateway) => Some(NatTopology::DoubleNat),
+            _ => None,
+        }
+    }
+}
+
+/// Detects the NAT topology comparing the WAN address reported by the local gateway sources
+/// (e.g. IGD or NAT-PMP) with the public address found by the public sources
+///
+/// # Arguments
+///
+/// * `public` - Consensus of the sources reporting the public address
+/// * `gateway` - Consensus of the sources asking the local gateway for its WAN address
+pub async fn detect_nat_topology(public: &Consensus, gateway: &Consensus) -> Option<NatTopology> {
+    let public = public.get_consensus().await?;
+    let local = local_address(public);
+    let gateway = gateway.get_consensus().await;
+    debug!(
+        "Public address {}, local address {:?}, gateway address {:?}",
+        public, local, gateway
+    );
+    NatTopology::classify(local, gateway, public)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::consensus::ConsensusBuilder;
+    use crate::consensus::tests::make_success;
+    use tokio_test::block_on;
+
+    const PUBLIC: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1));
+    const LAN: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2));
+
+    #[test]
+    fn test_classify() {
+        let shared = IpAddr::V4(Ipv4Addr::new(100, 64, 12, 1));
+        let private = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(
+            NatTopology::classify(Some(PUBLIC), None, PUBLIC),
+            Some(NatTopology::Direct)
+        );
+        assert_eq!(
+            NatTopology::classify(Some(LAN), Some(PUBLIC), PUBLIC),
+            Some(NatTopology::SingleNat)
+        );
+        assert_eq!(
+            NatTopology::classify(Some(LAN), Some(private), PUBLIC),
+            Some(NatTopology::DoubleNat)
+        );
+        assert_eq!(
+            NatTopology::classify(Some(LAN), Some(shared), PUBLIC),
+            Some(NatTopology::CarrierGradeNat)
+        );
+        assert_eq!(NatTopology::classify(Some(LAN), None, PUBLIC), None);
+    }
+
+    #[test]
+    fn test_classify_other_public_address() {
+        let other = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7));
+        assert_eq!(NatTopology::classify(Some(LAN), Some(other), PUBLIC), None);
+        let other = IpAddr::V6("2001:db8::1".parse().unwrap());
+        assert_eq!(NatTopology::classify(Some(LAN), Some(other), PUBLIC), None);
+        let unique_local = IpAddr::V6("fd00::1".parse().unwrap());
+        assert_eq!(
+            NatTopology::classify(Some(LAN), Some(unique_local), PUBLIC),
+            Some(NatTopology::DoubleNat)
+        );
+    }
+
+    #[test]
+    fn test_is_shared() {
+        assert!(is_shared(IpAddr::V4(Ipv4Addr::new(100, 64, 0, 0))));
+        assert!(is_shared(IpAddr::V4(Ipv4Addr::new(100, 127, 255, 255))));
+        assert!(!is_shared(IpAddr::V4(Ipv4Addr::new(100, 128, 0, 0))));
+        assert!(!is_shared(IpAddr::V4(Ipv4Addr::new(100, 63, 255, 255))));
+    }
+
+    #[test]
+    fn test_detect() {
+        let public = ConsensusBuilder::new()
+            .add_sources(vec![make_success(PUBLIC)])
+            .build();
+        let gateway = ConsensusBuilder::new()
+            .add_sources(vec![make_success(IpAddr::V4(Ipv4Addr::new(
+                100, 100, 1, 1,
+            )))])
+            .build();
+        assert_eq!(
+            block_on(detect_nat_topology(&public, &gateway)),
+            Some(NatTopology::CarrierGradeNat)
+        );
+    }
+}